@@ -0,0 +1,163 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Throughput of native messaging framing and of forwarding through the daemon, run with
+//! `cargo bench`. An argument only runs the benchmarks whose name contains it.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::os::unix::net::UnixListener;
+
+use nm_proxy::common;
+use nm_proxy::common::constants::*;
+use nm_proxy::common::runtime::Settings;
+use nm_proxy::common::{forward_frame_body, read_frame_length, FrameCodec, HandshakeMessage};
+use nm_proxy::daemon;
+use serde_json::Value;
+use tokio::net::UnixStream;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Message sizes benchmarked, in bytes of the JSON string payload
+const SIZES: [usize; 4] = [16, 1024, 64 * 1024, 1024 * 1024];
+
+/// Minimum duration that each benchmark is repeated for
+const TARGET_TIME: Duration = Duration::from_secs(1);
+
+#[tokio::main]
+async fn main() {
+    let filter = std::env::args().skip(1).find(|a| !a.starts_with("--"));
+    let enabled = |name: &str| filter.as_ref().is_none_or(|f| name.contains(f.as_str()));
+
+    for size in SIZES {
+        let message = Value::String("x".repeat(size));
+        let frame = frame(&message).await;
+
+        let name = format!("send_nm_object/{size}");
+        if enabled(&name) {
+            bench(&name, frame.len(), || async {
+                let mut buffer = Vec::with_capacity(frame.len());
+                common::send_nm_object(&mut buffer, &message).await.unwrap();
+            })
+            .await;
+        }
+
+        let name = format!("recv_nm_object/{size}");
+        if enabled(&name) {
+            bench(&name, frame.len(), || async {
+                let _: Value = common::recv_nm_object(&mut frame.as_slice()).await.unwrap();
+            })
+            .await;
+        }
+
+        for codec in [FrameCodec::Plain, FrameCodec::Compress] {
+            let name = format!("forward_frame_body/{codec:?}/{size}");
+            if enabled(&name) {
+                bench(&name, frame.len(), || forward(&frame, codec)).await;
+            }
+        }
+    }
+
+    let needed = SIZES.map(|size| format!("echo/{size}"));
+    if needed.iter().any(|n| enabled(n)) {
+        bench_echo(&enabled).await;
+    }
+}
+
+/// Serializes `message` into a native messaging frame
+async fn frame(message: &Value) -> Vec<u8> {
+    let mut frame = Vec::new();
+    common::send_nm_object(&mut frame, message).await.unwrap();
+    frame
+}
+
+/// Forwards a single `frame` into a buffer like the proxy client and daemon do
+async fn forward(frame: &[u8], codec: FrameCodec) {
+    let mut reader = frame;
+    let mut writer = Vec::with_capacity(frame.len());
+    let length = read_frame_length(&mut reader).await.unwrap().unwrap();
+    forward_frame_body(length, &mut reader, &mut writer, MAX_MESSAGE_SIZE, codec)
+        .await
+        .unwrap();
+}
+
+/// Round trips of messages through the daemon, with `cat` echoing them as the native binary
+async fn bench_echo(enabled: &impl Fn(&str) -> bool) {
+    let path = std::env::temp_dir().join(format!("nm-proxy-bench-{}.socket", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    listener.set_nonblocking(true).unwrap();
+
+    let settings = Settings {
+        native_binaries: HashMap::from([(
+            "firefox".into(),
+            HashMap::from([("a.json".into(), "/bin/cat".into())]),
+        )]),
+        daemon: Default::default(),
+    };
+    let token = CancellationToken::new();
+    let sockets = HashMap::from([("firefox".into(), listener.into())]);
+    let handle = tokio::spawn(daemon::run(sockets, settings, token.clone()));
+
+    let mut stream = UnixStream::connect(&path).await.unwrap();
+    let handshake = HandshakeMessage {
+        manifest_name: "a.json".into(),
+        args: vec![], // Would be taken as files by cat
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: MAX_MESSAGE_SIZE,
+        compression: false,
+        keepalive: false,
+        client_version: None,
+        reconnect: false,
+        terminate: false,
+        profile: None,
+    };
+    common::send_nm_object(&mut stream, &handshake)
+        .await
+        .unwrap();
+    let _: Value = common::recv_nm_object(&mut stream).await.unwrap();
+
+    // The connection is reused by every iteration
+    let stream = tokio::sync::Mutex::new(stream.into_split());
+    for size in SIZES {
+        let name = format!("echo/{size}");
+        if !enabled(&name) {
+            continue;
+        }
+
+        let message = Value::String("x".repeat(size));
+        let length = frame(&message).await.len();
+        bench(&name, 2 * length, || async {
+            // Large messages would fill the socket buffers if not received concurrently
+            let (reader, writer) = &mut *stream.lock().await;
+            let (sent, received) = tokio::join!(
+                common::send_nm_object(writer, &message),
+                common::recv_nm_object::<Value>(reader)
+            );
+            sent.and(received).unwrap();
+        })
+        .await;
+    }
+
+    drop(stream);
+    token.cancel();
+    handle.await.unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Repeats `f` for at least `TARGET_TIME` and prints its mean duration and the throughput of
+/// `bytes` processed per iteration
+async fn bench<F: Future<Output = ()>>(name: &str, bytes: usize, f: impl Fn() -> F) {
+    f().await; // Warm up
+
+    let start = Instant::now();
+    let mut iterations = 0u32;
+    while start.elapsed() < TARGET_TIME {
+        f().await;
+        iterations += 1;
+    }
+
+    let mean = start.elapsed() / iterations;
+    let throughput = bytes as f64 / mean.as_secs_f64() / (1024.0 * 1024.0);
+    println!("{name:<32} {mean:>12.2?} {throughput:>10.1} MiB/s ({iterations} iterations)");
+}
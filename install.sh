@@ -55,6 +55,8 @@ Description=nm-proxy daemon sockets
 Service=nm-proxy.service
 ListenStream=%t/nm-proxy-%I.socket
 FileDescriptorName=%I
+# Pending connections queued before the daemon accepts them, capped by net.core.somaxconn
+#Backlog=4096
 
 [Install]
 WantedBy=sockets.target
@@ -1,20 +1,30 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::HashSet;
 use std::env;
+use std::io::ErrorKind;
 use std::os::unix::fs::FileTypeExt;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use tokio::io::copy;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
+use tokio::signal::unix::{self, SignalKind};
 use tokio::task::JoinSet;
-use tokio::{fs, signal};
+use tokio::{fs, select, time};
 use tokio_fd::AsyncFd;
 
 use nm_proxy::common;
 use nm_proxy::common::constants::*;
+use nm_proxy::common::keepalive::Link;
 use nm_proxy::common::traits::*;
+use nm_proxy::common::{FrameCodec, HandshakeMessage, HandshakeReply};
+
+mod reconnect;
 
 async fn parse_args() -> Result<(String, Vec<String>)> {
     let mut args = env::args();
@@ -28,6 +38,7 @@ async fn parse_args() -> Result<(String, Vec<String>)> {
                 .to_os_string()
                 .into_string()
                 .map_err(|s| anyhow!("{:?}", s).context("Failed to parse file name"))?;
+            let manifest_path = absolute_path(manifest_path).await;
             return Ok((manifest_name, vec![manifest_path, app_id]));
         }
     }
@@ -39,7 +50,22 @@ async fn parse_args() -> Result<(String, Vec<String>)> {
     ))
 }
 
-async fn find_socket() -> Result<String> {
+/// Resolves `path` for the native binary, which runs in the working directory of the daemon.
+/// Paths that can't be canonicalized, e.g. because they don't exist, are only made absolute.
+async fn absolute_path(path: String) -> String {
+    let resolved = match fs::canonicalize(&path).await {
+        Ok(p) => p,
+        Err(_) => match std::path::absolute(&path) {
+            Ok(p) => p,
+            Err(_) => return path, // The working directory is gone, forward the path as is
+        },
+    };
+
+    resolved.into_os_string().into_string().unwrap_or(path)
+}
+
+/// Finds the first socket candidate in the runtime directory that is not in `skip`
+async fn find_socket(skip: &HashSet<String>) -> Result<String> {
     let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None)?;
     let mut stream = fs::read_dir(&runtime_dir)
         .await
@@ -61,57 +87,274 @@ async fn find_socket() -> Result<String> {
             && name.starts_with(SOCKET_PREFIX)
             && name.ends_with(SOCKET_SUFFIX)
         {
-            return Ok(entry.path().into_string_result()?);
+            let path = entry.path().into_string_result()?;
+            if !skip.contains(&path) {
+                return Ok(path);
+            }
         }
     }
 
     Err(anyhow!("No valid socket found in {}", runtime_dir))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let (manifest_name, args) = parse_args().await?;
-    let socket_path = find_socket().await?;
+/// Rescans of the runtime directory after every matching socket refused the connection,
+/// e.g. while the daemon restarts
+const SOCKET_RESCANS: u32 = 5;
+const SOCKET_RESCAN_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Connects to a matching socket, rescanning for other candidates if a stale one refuses.
+/// Once all of them have refused, they are rescanned a few times before giving up.
+async fn connect_socket() -> Result<UnixStream> {
+    let mut refused = HashSet::new();
+    let mut rescans = 0;
+    loop {
+        let socket_path = match find_socket(&refused).await {
+            Ok(p) => p,
+            Err(e) if refused.is_empty() => return Err(e),
+            Err(_) if rescans < SOCKET_RESCANS => {
+                rescans += 1;
+                refused.clear();
+                time::sleep(SOCKET_RESCAN_INTERVAL).await;
+                continue;
+            }
+            Err(e) => {
+                let mut refused: Vec<_> = refused.into_iter().collect();
+                refused.sort();
+                return Err(e.context(format!("Connection refused by {}", refused.join(", "))));
+            }
+        };
+
+        match UnixStream::connect(&socket_path).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                refused.insert(socket_path); // Stale socket, try the next one
+            }
+            Err(e) => {
+                return Err(e)
+                    .context(socket_path)
+                    .context("Failed to connect to socket")
+            }
+        }
+    }
+}
+
+/// Time allowed for marking the termination after a message being forwarded
+const TERMINATE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reason for the session to end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Exit {
+    /// A stream was closed
+    Closed,
+    /// Stopped by a signal
+    Signal,
+    /// The session exceeded the configured timeout
+    Timeout,
+}
+
+/// Optional limit on the duration of the whole session, for automated testing
+fn parse_timeout() -> Result<Option<Duration>> {
+    let value = common::parse_env(CLIENT_TIMEOUT_ENV, Some(""))?;
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    value
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .map(Some)
+        .ok_or_else(|| anyhow!("Invalid {CLIENT_TIMEOUT_ENV} \"{value}\", expected seconds"))
+}
+
+/// Optional window for reconnecting to the daemon after the connection breaks
+fn parse_reconnect() -> Result<Option<Duration>> {
+    let value = common::parse_env(CLIENT_RECONNECT_ENV, Some(""))?;
+    if value.is_empty() {
+        return Ok(None);
+    }
 
+    value
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .map(Some)
+        .ok_or_else(|| anyhow!("Invalid {CLIENT_RECONNECT_ENV} \"{value}\", expected seconds"))
+}
+
+/// Connects to the daemon and sends `handshake`, returning the split socket and the reply.
+/// There's no reply to legacy handshakes, nor when the daemon closes the connection like
+/// daemons predating a field of the handshake do.
+async fn send_handshake(
+    handshake: &HandshakeMessage,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf, Option<HandshakeReply>)> {
     // Connect to the socket
-    let stream = UnixStream::connect(&socket_path)
-        .await
-        .context(socket_path)
-        .context("Failed to connect to socket")?;
+    let stream = connect_socket().await?;
 
     // Split the socket stream into RX/TX
     let (mut socket_rx, mut socket_tx) = stream.into_split();
 
+    // Send handshake message to the socket
+    common::send_nm_object(&mut socket_tx, handshake)
+        .await
+        .context("Sending handshake message failed")?;
+    if handshake.protocol_version == 0 {
+        return Ok((socket_rx, socket_tx, None));
+    }
+
+    // Wait for the daemon to accept the handshake
+    match common::recv_nm_object(&mut socket_rx).await {
+        Ok(reply) => Ok((socket_rx, socket_tx, Some(reply))),
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof) =>
+        {
+            Ok((socket_rx, socket_tx, None))
+        }
+        Err(e) => Err(e).context("Receiving handshake reply failed"),
+    }
+}
+
+/// Connects to the daemon and performs the handshake, returning the split socket and reply.
+/// Daemons rejecting the handshake without a reply are retried with a legacy handshake.
+async fn connect(
+    handshake: &HandshakeMessage,
+) -> Result<(OwnedReadHalf, OwnedWriteHalf, HandshakeReply)> {
+    if let (socket_rx, socket_tx, Some(reply)) = send_handshake(handshake).await? {
+        if let Some(e) = reply.error {
+            return Err(anyhow!(e).context("Handshake rejected by daemon"));
+        }
+
+        return Ok((socket_rx, socket_tx, reply));
+    }
+
+    eprintln!("The daemon doesn't negotiate, falling back to a legacy handshake, update it");
+    let (socket_rx, socket_tx, _) = send_handshake(&handshake.legacy()).await?;
+
+    // Legacy daemons forward everything as is, as if they had declined all features
+    let reply = HandshakeReply {
+        protocol_version: 0,
+        max_message_size: MAX_MESSAGE_SIZE,
+        error: None,
+        compression: false,
+        keepalive_interval: None,
+        reconnect: false,
+        terminate: false,
+    };
+    Ok((socket_rx, socket_tx, reply))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let (manifest_name, args) = parse_args().await?;
+    let timeout = parse_timeout()?;
+    let reconnect = parse_reconnect()?;
+
+    // Construct the handshake message, buffered for reconnecting
+    let handshake = HandshakeMessage {
+        manifest_name,
+        args,
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: MAX_MESSAGE_SIZE,
+        compression: true,              // The daemon decides whether to use these
+        keepalive: reconnect.is_none(), // A broken connection is reconnected instead
+        client_version: Some(env!("CARGO_PKG_VERSION").into()),
+        reconnect: reconnect.is_some(),
+        terminate: reconnect.is_none(), // Reconnecting sessions only forward whole messages
+        profile: Some(common::parse_env(PROFILE_ENV, Some(""))?).filter(|p| !p.is_empty()),
+    };
+    let (mut socket_rx, mut socket_tx, reply) = connect(&handshake).await?;
+
+    let mut set = JoinSet::new();
+    if let Some(window) = reconnect {
+        let connection = (socket_rx, socket_tx, reply);
+        set.spawn(reconnect::run(handshake, connection, window));
+        return wait(set, timeout, None).await;
+    }
+
     let mut stdin =
         AsyncFd::try_from(libc::STDIN_FILENO).context("Unable to asynchronously open stdin")?;
     let mut stdout =
         AsyncFd::try_from(libc::STDOUT_FILENO).context("Unable to asynchronously open stdout")?;
 
-    // Construct and send handshake message to the socket
-    common::send_nm_object(
-        &mut socket_tx,
-        common::HandshakeMessage {
-            manifest_name,
-            args,
-        },
-    )
-    .await
-    .context("Sending handshake message failed")?;
+    // Spawn bidirectional asynchronous copy tasks, compression, keepalive and marking the
+    // termination need framing
+    if reply.compression || reply.keepalive_interval.is_some() || reply.terminate {
+        let max_size = reply.max_frame_size();
+        let (to_daemon, from_daemon) = match reply.compression {
+            true => (FrameCodec::Compress, FrameCodec::Decompress),
+            false => (FrameCodec::Plain, FrameCodec::Plain),
+        };
 
-    // Spawn bidirectional asynchronous copy tasks
-    let mut set = JoinSet::new();
-    set.spawn(async move { copy(&mut stdin, &mut socket_tx).await.map(|_| false) });
-    set.spawn(async move { copy(&mut socket_rx, &mut stdout).await.map(|_| false) });
+        // Shared between forwarded data and control frames
+        let link = Arc::new(Link::new(socket_tx));
+        let link_clone = link.clone();
+        set.spawn(async move {
+            link_clone
+                .forward_from(&mut stdin, (max_size, to_daemon))
+                .await
+                .map(|_| Exit::Closed)
+        });
+        let link_clone = link.clone();
+        set.spawn(async move {
+            link_clone
+                .forward_to(&mut socket_rx, &mut stdout, (max_size, from_daemon))
+                .await
+                .map(|_| Exit::Closed)
+        });
+        if let Some(interval) = reply.keepalive_interval {
+            let link = link.clone();
+            let interval = Duration::from_secs(interval);
+            set.spawn(async move { link.keepalive(interval).await.map(|_| Exit::Closed) });
+        }
 
-    // Graceful shutdown helper task
-    set.spawn(async move { signal::ctrl_c().await.map(|_| true) });
+        return wait(set, timeout, Some(link).filter(|_| reply.terminate)).await;
+    } else {
+        set.spawn(async move { copy(&mut stdin, &mut socket_tx).await.map(|_| Exit::Closed) });
+        set.spawn(async move {
+            copy(&mut socket_rx, &mut stdout)
+                .await
+                .map(|_| Exit::Closed)
+        });
+    }
+
+    wait(set, timeout, None).await
+}
+
+/// Waits for the forwarding tasks in `set` to finish, a signal, or the session timeout. When
+/// stopped by a signal, the termination is marked over `link` so that the daemon stops the
+/// native binary right away instead of noticing the connection close.
+async fn wait(
+    mut set: JoinSet<std::io::Result<Exit>>,
+    timeout: Option<Duration>,
+    link: Option<Arc<Link<OwnedWriteHalf>>>,
+) -> Result<()> {
+    // Graceful shutdown helper task, browsers terminate their native messaging hosts
+    let mut interrupt = unix::signal(SignalKind::interrupt())?;
+    let mut terminate = unix::signal(SignalKind::terminate())?;
+    set.spawn(async move {
+        select! {
+            _ = interrupt.recv() => (),
+            _ = terminate.recv() => (),
+        }
+        Ok(Exit::Signal)
+    });
+
+    if let Some(timeout) = timeout {
+        set.spawn(async move {
+            time::sleep(timeout).await;
+            eprintln!("Session timed out after {timeout:?}");
+            Ok(Exit::Timeout)
+        });
+    }
 
     // Wait for any one of the tasks and then abort all others
     let mut aborted = false;
-    let mut graceful = false;
+    let mut exit = Exit::Closed;
     while let Some(result) = set.join_next().await {
         match result {
-            Ok(Ok(signal)) => graceful |= signal,
+            Ok(Ok(Exit::Closed)) => (),
+            Ok(Ok(e)) => exit = e,
             Ok(Err(e)) => Err(e).context("Task encountered error")?,
             Err(e) if e.is_cancelled() => (), // Cancellations are expected
             Err(e) => Err(e).context("Unexpected error when joining task")?,
@@ -120,13 +363,22 @@ async fn main() -> Result<()> {
         // Abort all tasks after first task has finished
         if !aborted {
             aborted = true;
+            if let (Exit::Signal, Some(link)) = (exit, &link) {
+                // Waits for the message being forwarded, if any, so that the frame is complete
+                if time::timeout(TERMINATE_TIMEOUT, link.send_terminate())
+                    .await
+                    .is_err()
+                {
+                    eprintln!("Unable to mark the termination, a message is stuck");
+                }
+            }
             set.abort_all();
         }
     }
 
-    if graceful {
-        Ok(())
-    } else {
-        Err(anyhow!("Unclean shutdown, did the socket close?"))
+    match exit {
+        Exit::Signal => Ok(()),
+        Exit::Timeout => std::process::exit(CLIENT_TIMEOUT_EXIT_CODE),
+        Exit::Closed => Err(anyhow!("Unclean shutdown, did the socket close?")),
     }
 }
@@ -0,0 +1,193 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::select;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time::{self, Duration, Instant};
+use tokio_fd::AsyncFd;
+
+use nm_proxy::common::keepalive::{CONTROL_FLAG, MAX_LINK_FRAME_SIZE, SESSION_END};
+use nm_proxy::common::{
+    forward_frame_body, read_frame_length, FrameCodec, HandshakeMessage, HandshakeReply,
+};
+
+use crate::{connect, Exit};
+
+/// Delay between attempts to reconnect to the daemon
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Messages buffered in each direction, e.g. while reconnecting
+const BUFFERED_MESSAGES: usize = 16;
+
+/// How forwarding over a single connection to the daemon ended
+enum Outcome {
+    /// The browser closed stdin
+    Closed,
+    /// The daemon marked the end of the session
+    Ended,
+    /// The connection broke without the daemon ending the session
+    Broken(IoError),
+}
+
+/// Forwards whole messages between the browser and the daemon over `connection`. If the
+/// connection breaks without the daemon marking the end of the session, reconnects and
+/// repeats the handshake for up to `window`. This launches the native binary anew, unless
+/// it is persistent, and messages that were in flight may be lost.
+pub async fn run(
+    handshake: HandshakeMessage,
+    connection: (OwnedReadHalf, OwnedWriteHalf, HandshakeReply),
+    window: Duration,
+) -> IoResult<Exit> {
+    let stdin = AsyncFd::try_from(libc::STDIN_FILENO)?;
+    let stdout = AsyncFd::try_from(libc::STDOUT_FILENO)?;
+
+    // Messages are only forwarded whole, so that none is split between connections
+    let (to_daemon, mut from_browser) = mpsc::channel(BUFFERED_MESSAGES);
+    let (to_browser, from_daemon) = mpsc::channel(BUFFERED_MESSAGES);
+    let reader = tokio::spawn(read_messages(stdin, to_daemon));
+    let writer = tokio::spawn(write_messages(stdout, from_daemon));
+
+    let (mut socket_rx, mut socket_tx, mut reply) = connection;
+    let mut pending = None; // Message whose sending failed, retried over the next connection
+    loop {
+        if !reply.reconnect {
+            eprintln!("The daemon doesn't support reconnecting, update it to reconnect");
+        }
+
+        let max_size = reply.max_frame_size();
+        let (to_daemon, from_daemon) = match reply.compression {
+            true => (
+                (max_size, FrameCodec::Compress),
+                (max_size, FrameCodec::Decompress),
+            ),
+            false => ((max_size, FrameCodec::Plain), (max_size, FrameCodec::Plain)),
+        };
+
+        let outcome = select! {
+            res = send_messages(&mut from_browser, &mut pending, &mut socket_tx, to_daemon) => res?,
+            res = recv_messages(&mut socket_rx, &to_browser, from_daemon) => res?,
+        };
+
+        let error = match outcome {
+            Outcome::Broken(e) if reply.reconnect => e,
+            Outcome::Broken(e) => return Err(e),
+            Outcome::Closed => {
+                reader.await??; // Surface stdin errors, which also close it
+                return Ok(Exit::Closed);
+            }
+            Outcome::Ended => {
+                // Let the browser receive the last messages
+                drop(to_browser);
+                writer.await??;
+                return Ok(Exit::Closed);
+            }
+        };
+
+        eprintln!("Connection to the daemon broke, reconnecting: {error}");
+        let deadline = Instant::now() + window;
+        (socket_rx, socket_tx, reply) = loop {
+            match connect(&handshake).await {
+                Ok(connection) => break connection,
+                Err(e) if Instant::now() >= deadline => {
+                    return Err(IoError::other(format!(
+                        "Unable to reconnect within {window:?}: {e:#}"
+                    )));
+                }
+                Err(_) => time::sleep(RETRY_INTERVAL).await,
+            }
+        };
+        eprintln!("Reconnected to the daemon");
+    }
+}
+
+/// Reads whole messages from the browser until it closes stdin, they are sent over links
+/// carrying control frames
+async fn read_messages(mut stdin: AsyncFd, messages: Sender<Vec<u8>>) -> IoResult<()> {
+    while let Some(length) = read_frame_length(&mut stdin).await? {
+        let mut message = Vec::new();
+        let codec = FrameCodec::Plain;
+        forward_frame_body(length, &mut stdin, &mut message, MAX_LINK_FRAME_SIZE, codec).await?;
+        if messages.send(message).await.is_err() {
+            break; // Session ended
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes whole messages to the browser, so that they are never cut off by reconnecting
+async fn write_messages(mut stdout: AsyncFd, mut messages: Receiver<Vec<u8>>) -> IoResult<()> {
+    while let Some(message) = messages.recv().await {
+        stdout.write_all(&message).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends messages from the browser to the daemon, keeping the one being sent in `pending`
+async fn send_messages(
+    messages: &mut Receiver<Vec<u8>>,
+    pending: &mut Option<Vec<u8>>,
+    socket: &mut OwnedWriteHalf,
+    (max_size, codec): (u32, FrameCodec),
+) -> IoResult<Outcome> {
+    loop {
+        let message = match pending {
+            Some(message) => message,
+            None => match messages.recv().await {
+                Some(message) => pending.insert(message),
+                None => return Ok(Outcome::Closed),
+            },
+        };
+
+        // Messages are encoded again for each connection, it may negotiate a different codec
+        let body = &message[std::mem::size_of::<u32>()..];
+        let mut frame = Vec::new();
+        forward_frame_body(body.len() as u32, &mut &*body, &mut frame, max_size, codec).await?;
+        if let Err(e) = socket.write_all(&frame).await {
+            return Ok(Outcome::Broken(e));
+        }
+
+        *pending = None;
+    }
+}
+
+/// Receives messages from the daemon until it marks the end of the session
+async fn recv_messages(
+    socket: &mut OwnedReadHalf,
+    messages: &Sender<Vec<u8>>,
+    (max_size, codec): (u32, FrameCodec),
+) -> IoResult<Outcome> {
+    loop {
+        let length = match read_frame_length(socket).await {
+            Ok(Some(SESSION_END)) => return Ok(Outcome::Ended),
+            Ok(Some(l)) if l & CONTROL_FLAG != 0 => {
+                return Err(IoError::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown control frame {l:#x}"),
+                ));
+            }
+            Ok(Some(l)) => l,
+            Ok(None) => return Ok(Outcome::Broken(ErrorKind::UnexpectedEof.into())),
+            Err(e) => return Ok(Outcome::Broken(e)),
+        };
+
+        let mut message = Vec::new();
+        match forward_frame_body(length, socket, &mut message, max_size, codec).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::InvalidData => return Err(e),
+            Err(e) => return Ok(Outcome::Broken(e)),
+        }
+
+        if messages.send(message).await.is_err() {
+            return Err(IoError::new(
+                ErrorKind::BrokenPipe,
+                "Unable to write to stdout",
+            ));
+        }
+    }
+}
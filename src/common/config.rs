@@ -3,14 +3,22 @@
 
 use crate::common;
 use crate::common::constants::*;
-use anyhow::{Context, Error, Result};
+use crate::common::manifest;
+use crate::common::runtime::{
+    DaemonSettings, LogLevel, ManifestMode, ManifestSettings, StderrMode,
+};
+use crate::common::traits::*;
+use anyhow::{anyhow, bail, Context, Result};
 use expanduser::expanduser;
 use serde::de::Error as DeError;
 use serde::{Deserialize, Deserializer};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::ErrorKind;
+use std::num::NonZeroUsize;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
-use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::fs;
 
 const CONFIG_HELP: &str = concat!(
     r#"
@@ -28,6 +36,8 @@ Ensure that it is present, and contains the following:
 # app_id = "app.example.com" # Flatpak 3-part app ID
 # nmh_dir = ".<name>/native-messaging-hosts" # Native messaging host application directory
 #
+# All other options are described in the Configuration section of the README.
+#
 # Example configuration:
 
 [daemon]
@@ -46,42 +56,354 @@ app_id = "org.chromium.Chromium"
 nmh_dir = ".config/chromium/NativeMessagingHosts""#
 );
 
+/// Strategy for pointing deployed app manifests at the proxy client
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientDeployment {
+    /// Copy the proxy client into each NMH directory (default)
+    #[default]
+    Copy,
+    /// Point all manifests directly at the configured proxy client
+    Shared,
+}
+
+/// Serialization of deployed app manifests
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestStyle {
+    /// Indented, one key per line (default)
+    #[default]
+    Pretty,
+    /// Everything on a single line
+    Compact,
+}
+
+/// Handling of native binary paths of source app manifests that are symlinks
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BinarySymlinks {
+    /// Register the path as is (default)
+    #[default]
+    Keep,
+    /// Register the real path that the symlink resolves to
+    Resolve,
+    /// Fail setup for symlinks resolving outside of `symlink_targets`
+    Reject,
+}
+
+impl ManifestStyle {
+    pub fn serialize(self, manifest: &serde_json::Value) -> serde_json::Result<Vec<u8>> {
+        match self {
+            Self::Pretty => serde_json::to_vec_pretty(manifest),
+            Self::Compact => serde_json::to_vec(manifest),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)] // Strict mode
 struct DaemonConfig {
     #[serde(deserialize_with = "path_parser")]
     proxy_client: PathBuf,
+    #[serde(default)]
+    client_deployment: ClientDeployment,
+    stderr_warn_lines: Option<usize>,
+    allowed_uid: Option<u32>,
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    on_launch: Option<PathBuf>,
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    event_stream: Option<PathBuf>,
+    #[serde(default)]
+    compression: bool,
+    keepalive_interval: Option<u64>,
+    #[serde(default)]
+    stderr: StderrMode,
+    accept_log_level: Option<LogLevel>,
+    workers: Option<NonZeroUsize>,
+    queue_warn_depth: Option<usize>,
+    shutdown_timeout: Option<u64>,
+    crash_window_ms: Option<u64>,
+    shutdown_message: Option<serde_json::Value>,
+    #[serde(default)]
+    listener_restarts: u32,
+    idle_exit: Option<u64>,
+    backlog: Option<u32>,
+    #[serde(default)]
+    env_remove: Vec<String>,
+    latency_threshold_ms: Option<u64>,
+    #[serde(default)]
+    extension_id_env: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)] // Strict mode
+struct SetupConfig {
+    #[serde(default)]
+    allow_comments: bool,
+    #[serde(default, deserialize_with = "path_list_parser")]
+    manifest_dirs: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    manifest_style: ManifestStyle,
+    #[cfg_attr(target_os = "macos", allow(dead_code))] // Flatpak only
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    flatpak_app_base: Option<PathBuf>,
+    #[serde(default)]
+    binary_symlinks: BinarySymlinks,
+    #[serde(default, deserialize_with = "path_list_parser")]
+    symlink_targets: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    require_parent_dirs: bool,
+    #[serde(default)]
+    strict_permissions: bool,
+    write_retries: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)] // Strict mode
+struct LoggingConfig {
+    level: Option<LogLevel>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)] // Strict mode
 struct BrowserConfig {
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[cfg_attr(target_os = "macos", allow(dead_code))] // Flatpak only
     app_id: String,
     nmh_dir: String,
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))] // macOS only
+    macos_nmh_dir: Option<String>,
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    require_path: Option<PathBuf>,
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    proxy_client: Option<PathBuf>,
+    /// Values set in the deployed app manifests by JSON pointer
+    #[serde(default)]
+    manifest_overrides: BTreeMap<String, serde_json::Value>,
+    /// Name of the socket passed by systemd, defaults to the browser name
+    socket_name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)] // Strict mode
+struct OverrideConfig {
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    binary: Option<PathBuf>,
+    #[serde(default, deserialize_with = "path_list_parser")]
+    pass_fds: Option<Vec<PathBuf>>,
+    persistent_ttl: Option<u64>,
+    stderr: Option<StderrMode>,
+    max_output_size: Option<u32>,
+    #[serde(default)]
+    debug_output: bool,
+    memory_limit: Option<u64>,
+    cpu_time_limit: Option<u64>,
+    open_files_limit: Option<u64>,
+    #[serde(default)]
+    mode: ManifestMode,
+    #[serde(default, deserialize_with = "optional_path_parser")]
+    capture_dir: Option<PathBuf>,
 }
 
 #[derive(Deserialize, Debug)]
 #[serde(deny_unknown_fields)] // Strict mode
 pub struct Config {
     daemon: DaemonConfig,
+    #[serde(default)]
+    setup: SetupConfig,
+    #[serde(default)]
+    logging: LoggingConfig,
     browsers: HashMap<String, BrowserConfig>,
+    #[serde(default)]
+    overrides: HashMap<String, OverrideConfig>,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl BrowserConfig {
+    /// Flatpak app ID of the browser named `browser`, with templates expanded
+    #[cfg(not(target_os = "macos"))]
+    fn app_id(&self, browser: &str) -> Result<String> {
+        expand_template(&self.app_id, browser)
+            .with_context(|| format!("Invalid app_id of browser {browser}"))
+    }
+
+    /// NMH directory of the browser named `browser` relative to its app directory on Linux,
+    /// or to Application Support on macOS, with templates expanded
+    fn nmh_dir(&self, browser: &str) -> Result<String> {
+        #[cfg(target_os = "macos")]
+        if let Some(dir) = &self.macos_nmh_dir {
+            return expand_template(dir, browser)
+                .with_context(|| format!("Invalid macos_nmh_dir of browser {browser}"));
+        }
+        expand_template(&self.nmh_dir, browser)
+            .with_context(|| format!("Invalid nmh_dir of browser {browser}"))
+    }
+}
+
+/// Expands `{browser}` in a browser configuration value to the name of the browser
+fn expand_template(value: &str, browser: &str) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|i| start + i)
+            .ok_or_else(|| anyhow!("Unterminated template variable in \"{value}\""))?;
+        match &rest[start + 1..end] {
+            "browser" => expanded.push_str(browser),
+            v => bail!(
+                "Unknown template variable {{{v}}} in \"{value}\", only {{browser}} is supported"
+            ),
+        }
+        rest = &rest[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Expands environment variables, written as `$VAR` or `${VAR}`, and a leading `~` in a
+/// path. Undefined variables are an error, `$$` stands for a literal `$`.
+pub fn expand_path(path: &str) -> Result<PathBuf> {
+    expand_path_with(path, |name| common::parse_env(name, None))
+}
+
+/// Expands `path` like `expand_path`, looking variables up with `var` instead of in the
+/// environment of the process
+pub fn expand_path_with(path: &str, var: impl Fn(&str) -> Result<String>) -> Result<PathBuf> {
+    let mut expanded = String::new();
+    let mut rest = path;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = r;
+            continue;
+        }
+
+        let (name, len) = match rest.strip_prefix('{') {
+            Some(r) => {
+                let end = r
+                    .find('}')
+                    .ok_or_else(|| anyhow!("Unterminated variable in \"{path}\""))?;
+                (&r[..end], end + 2)
+            }
+            None => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        if name.is_empty() {
+            bail!("Missing variable name in \"{path}\", write $$ for a literal $");
+        }
+
+        let value = var(name).with_context(|| format!("Unable to expand \"{path}\""))?;
+        expanded.push_str(&value);
+        rest = &rest[len..];
+    }
+
+    expanded.push_str(rest);
+    expanduser(expanded).with_context(|| format!("Unable to expand \"{path}\""))
 }
 
 impl Config {
+    fn enabled_browsers(&self) -> impl Iterator<Item = (&String, &BrowserConfig)> {
+        self.browsers.iter().filter(|(_, c)| c.enabled)
+    }
+
     pub fn browsers(&self) -> impl Iterator<Item = &String> {
-        self.browsers.keys()
+        self.enabled_browsers().map(|(n, _)| n)
     }
 
+    /// Disables the browsers whose `require_path` doesn't exist on this machine,
+    /// returning them along with the missing path
+    pub fn disable_unavailable_browsers(&mut self) -> Vec<(String, PathBuf)> {
+        let mut disabled: Vec<_> = self
+            .browsers
+            .iter_mut()
+            .filter(|(_, c)| c.enabled)
+            .filter_map(|(n, c)| {
+                let path = c.require_path.as_ref().filter(|p| !p.exists())?;
+                c.enabled = false;
+                Some((n.clone(), path.clone()))
+            })
+            .collect();
+
+        disabled.sort();
+        disabled
+    }
+
+    /// Disables `browser` for the rest of the run, e.g. after its setup failed
+    pub fn disable_browser(&mut self, browser: &str) {
+        if let Some(c) = self.browsers.get_mut(browser) {
+            c.enabled = false;
+        }
+    }
+
+    /// Directory that NMH directories are relative to, after the app ID on Linux. The
+    /// environment takes precedence over `flatpak_app_base` of Linux, e.g. for redirecting
+    /// setup in tests.
+    pub fn nmh_base_dir(&self) -> Result<PathBuf> {
+        #[cfg(not(target_os = "macos"))]
+        if let (None, Some(dir)) = (env::var_os(NMH_BASE_DIR_ENV), &self.setup.flatpak_app_base) {
+            return Ok(dir.clone());
+        }
+        default_nmh_base_dir()
+    }
+
+    /// Native messaging host directories of the browsers, inside the Flatpak app
+    /// directories on Linux and relative to Application Support on macOS
     pub fn nmh_dirs(&self) -> Result<impl Iterator<Item = (&String, PathBuf)> + '_> {
-        let app_dir = expanduser("~/.var/app").context("Path expansion failed")?;
+        let base_dir = self.nmh_base_dir()?;
+        let dirs = self
+            .enabled_browsers()
+            .map(|(n, c)| {
+                let nmh_dir = c.nmh_dir(n)?;
+
+                // Joining an absolute path would replace the app directory instead
+                if Path::new(&nmh_dir).is_absolute() {
+                    bail!(
+                        "NMH directory of browser {n} must be relative to its app directory, \
+                        found absolute path {nmh_dir}"
+                    );
+                }
+                #[cfg(not(target_os = "macos"))]
+                let d = base_dir.join(c.app_id(n)?).join(nmh_dir);
+                #[cfg(target_os = "macos")]
+                let d = base_dir.join(nmh_dir);
+                Ok((n, d))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(dirs.into_iter())
+    }
 
-        Ok(self.browsers.iter().map(move |(n, c)| {
-            let mut d = app_dir.join(&c.app_id);
-            d.push(&c.nmh_dir);
-            (n, d)
-        }))
+    /// Browsers whose native messaging host directories resolve to the same path, which
+    /// only need to be deployed once
+    pub fn shared_nmh_dirs(&self) -> Result<Vec<(PathBuf, Vec<&String>)>> {
+        let mut dirs = BTreeMap::<_, Vec<_>>::new();
+        for (browser, nmh_dir) in self.nmh_dirs()? {
+            dirs.entry(nmh_dir).or_default().push(browser);
+        }
+
+        Ok(dirs
+            .into_iter()
+            .filter(|(_, browsers)| browsers.len() > 1)
+            .map(|(nmh_dir, mut browsers)| {
+                browsers.sort();
+                (nmh_dir, browsers)
+            })
+            .collect())
     }
 
+    #[cfg(target_os = "linux")]
     pub fn override_paths(&self) -> Result<impl Iterator<Item = (&String, PathBuf)> + '_> {
         let mut config_dir =
             expanduser(common::parse_env("XDG_DATA_HOME", Some("~/.local/share"))?)
@@ -89,42 +411,369 @@ impl Config {
         config_dir.push("flatpak");
         config_dir.push("overrides");
 
-        Ok(self
-            .browsers
-            .iter()
-            .map(move |(n, c)| (n, config_dir.join(&c.app_id))))
+        let paths = self
+            .enabled_browsers()
+            .map(|(n, c)| Ok((n, config_dir.join(c.app_id(n)?))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(paths.into_iter())
+    }
+
+    /// Native binary configured to replace the "path" of the given app manifest
+    pub fn binary_override(&self, manifest_name: &str) -> Option<&PathBuf> {
+        self.overrides.get(manifest_name)?.binary.as_ref()
+    }
+
+    /// Proxy client deployed for `browser`, or the daemon-wide one if not given or overridden
+    pub fn proxy_client_path(&self, browser: Option<&str>) -> &PathBuf {
+        browser
+            .and_then(|b| self.browsers.get(b)?.proxy_client.as_ref())
+            .unwrap_or(&self.daemon.proxy_client)
+    }
+
+    /// Values to set in the app manifests deployed for `browser`, by JSON pointer
+    pub fn manifest_overrides(
+        &self,
+        browser: &str,
+    ) -> impl Iterator<Item = (&String, &serde_json::Value)> {
+        self.browsers
+            .get(browser)
+            .into_iter()
+            .flat_map(|b| &b.manifest_overrides)
+    }
+
+    /// Default log level of the daemon and setup when RUST_LOG is unset
+    pub fn log_level(&self) -> Option<LogLevel> {
+        self.logging.level
+    }
+
+    pub fn allow_manifest_comments(&self) -> bool {
+        self.setup.allow_comments
+    }
+
+    pub fn manifest_style(&self) -> ManifestStyle {
+        self.setup.manifest_style
     }
 
-    pub fn proxy_client_path(&self) -> &PathBuf {
-        &self.daemon.proxy_client
+    /// Whether NMH directories may only be created in existing parent directories
+    pub fn require_parent_dirs(&self) -> bool {
+        self.setup.require_parent_dirs
+    }
+
+    /// Times a deployment write failing with a transient error is retried
+    pub fn write_retries(&self) -> u32 {
+        self.setup.write_retries.unwrap_or(3)
+    }
+
+    /// Whether configuration files and app manifests that other users can modify are refused
+    pub fn strict_permissions(&self) -> bool {
+        self.setup.strict_permissions
+    }
+
+    /// Native binary path to register for `binary`, resolving or rejecting it as configured
+    /// if it is a symlink. Missing binaries are kept as is.
+    pub fn check_binary(&self, binary: &str) -> Result<String> {
+        let mode = self.setup.binary_symlinks;
+        let is_symlink = std::fs::symlink_metadata(binary).is_ok_and(|m| m.is_symlink());
+        if mode == BinarySymlinks::Keep || !is_symlink {
+            return Ok(binary.into());
+        }
+
+        let target = std::fs::canonicalize(binary)
+            .with_context(|| binary.to_string())
+            .context("Unable to resolve native binary symlink")?;
+        match mode {
+            BinarySymlinks::Reject => {
+                let allowed = self.setup.symlink_targets.iter().flatten();
+                if !allowed.into_iter().any(|p| target.starts_with(p)) {
+                    bail!(
+                        "Native binary {binary} is a symlink to {}, which is outside of symlink_targets",
+                        target.display()
+                    );
+                }
+                Ok(binary.into())
+            }
+            _ => target
+                .into_os_string()
+                .into_string()
+                .map_err(|t| anyhow!("Native binary {binary} resolves to non-UTF-8 path {t:?}")),
+        }
+    }
+
+    /// Manifest source directories in ascending order of precedence, relative
+    /// paths are resolved against the configuration directory `config_path`
+    pub fn manifest_dirs(&self, config_path: impl AsRef<Path>) -> Vec<PathBuf> {
+        let config_path = config_path.as_ref();
+        match &self.setup.manifest_dirs {
+            Some(dirs) => dirs.iter().map(|d| config_path.join(d)).collect(),
+            None => vec![config_path.join(APP_MANIFEST_DIR)],
+        }
+    }
+
+    /// Runtime settings that are passed on to the daemon
+    pub fn daemon_settings(&self) -> DaemonSettings {
+        let defaults = DaemonSettings::default();
+        DaemonSettings {
+            stderr_warn_lines: self
+                .daemon
+                .stderr_warn_lines
+                .unwrap_or(defaults.stderr_warn_lines),
+            allowed_uid: self.daemon.allowed_uid,
+            on_launch: self.daemon.on_launch.clone(),
+            event_stream: self.daemon.event_stream.clone(),
+            compression: self.daemon.compression,
+            keepalive_interval: self.daemon.keepalive_interval,
+            stderr: self.daemon.stderr.clone(),
+            accept_log_level: self
+                .daemon
+                .accept_log_level
+                .unwrap_or(defaults.accept_log_level),
+            workers: self.daemon.workers,
+            queue_warn_depth: self.daemon.queue_warn_depth,
+            shutdown_timeout: self
+                .daemon
+                .shutdown_timeout
+                .unwrap_or(defaults.shutdown_timeout),
+            crash_window_ms: self
+                .daemon
+                .crash_window_ms
+                .unwrap_or(defaults.crash_window_ms),
+            listener_restarts: self.daemon.listener_restarts,
+            idle_exit: self.daemon.idle_exit,
+            shutdown_message: self.daemon.shutdown_message.clone(),
+            backlog: self.daemon.backlog,
+            env_remove: self.daemon.env_remove.clone(),
+            latency_threshold_ms: self.daemon.latency_threshold_ms,
+            log_level: self.logging.level,
+            extension_id_env: self.daemon.extension_id_env,
+            socket_names: self
+                .enabled_browsers()
+                .filter_map(|(name, b)| Some((name.clone(), b.socket_name.clone()?)))
+                .collect(),
+            manifests: self
+                .overrides
+                .iter()
+                .map(|(name, o)| {
+                    let settings = ManifestSettings {
+                        pass_fds: o.pass_fds.clone().unwrap_or_default(),
+                        persistent_ttl: o.persistent_ttl,
+                        stderr: o.stderr.clone(),
+                        max_output_size: o.max_output_size,
+                        debug_output: o.debug_output,
+                        memory_limit: o.memory_limit,
+                        cpu_time_limit: o.cpu_time_limit,
+                        open_files_limit: o.open_files_limit,
+                        mode: o.mode,
+                        capture_dir: o.capture_dir.clone(),
+                    };
+                    (name.clone(), settings)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn client_deployment(&self) -> ClientDeployment {
+        self.daemon.client_deployment
+    }
+
+    /// Path of the proxy client that manifests deployed into `nmh_dir` of `browser` should point to
+    pub fn manifest_client_path(
+        &self,
+        browser: Option<&str>,
+        nmh_dir: impl AsRef<Path>,
+    ) -> PathBuf {
+        match self.daemon.client_deployment {
+            ClientDeployment::Copy => nmh_dir.as_ref().join(PROXY_CLIENT_BIN),
+            ClientDeployment::Shared => self.proxy_client_path(browser).clone(),
+        }
     }
 }
 
 /// Parse (expand) paths during deserialization
 fn path_parser<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
     let s: String = Deserialize::deserialize(deserializer)?;
-    expanduser(s).map_err(|e| D::Error::custom(e))
+    expand_path(&s).map_err(|e| D::Error::custom(format!("{e:#}")))
 }
 
-pub async fn form_config_path() -> Result<PathBuf> {
+/// Parse (expand) optional paths during deserialization
+fn optional_path_parser<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<PathBuf>, D::Error> {
+    path_parser(deserializer).map(Some)
+}
+
+/// Parse (expand) optional lists of paths during deserialization
+fn path_list_parser<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<PathBuf>>, D::Error> {
+    let list: Vec<String> = Deserialize::deserialize(deserializer)?;
+    list.into_iter()
+        .map(|s| expand_path(&s).map_err(|e| D::Error::custom(format!("{e:#}"))))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Directory that `nmh_dir`s are relative to without a configuration, which only the
+/// environment overrides
+pub fn default_nmh_base_dir() -> Result<PathBuf> {
+    let dir = match env::var_os(NMH_BASE_DIR_ENV) {
+        Some(dir) => expanduser(dir.into_string_result()?),
+        None => expanduser(NMH_BASE_DIR),
+    };
+    dir.context("Path expansion failed")
+}
+
+/// Configuration directory of the user, which may not exist yet
+pub fn config_dir() -> Result<PathBuf> {
     let mut path = expanduser(common::parse_env("XDG_CONFIG_HOME", Some("~/.config"))?)
         .context("Configuration file path expansion failed")?;
     path.push(CONFIG_DIR);
-    path.canonicalize()
-        .context("Configuration file path canonicalization failed")
+    Ok(path)
 }
 
-async fn read_config(config_path: &Path) -> Result<Config> {
-    let mut config_file = File::open(&config_path).await?;
-    let mut contents = String::new();
-    config_file.read_to_string(&mut contents).await?;
-    toml::from_str(&contents).map_err(|e| Error::from(e))
+/// State directory of the user for data kept between setup runs, which may not exist yet
+pub fn state_dir() -> Result<PathBuf> {
+    let mut path = expanduser(common::parse_env("XDG_STATE_HOME", Some("~/.local/state"))?)
+        .context("State directory path expansion failed")?;
+    path.push(CONFIG_DIR);
+    Ok(path)
+}
+
+pub async fn form_config_path() -> Result<PathBuf> {
+    let path = config_dir()?;
+    match path.canonicalize() {
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(anyhow!(
+            "Configuration directory {} does not exist, create it and place {CONFIG_FILE} there",
+            path.display()
+        ))
+        .context(CONFIG_HELP),
+        result => result
+            .with_context(|| path.display().to_string())
+            .context("Configuration file path canonicalization failed"),
+    }
 }
 
-pub async fn load_config(path: impl AsRef<Path>) -> Result<Config> {
-    let path = path.as_ref().join(CONFIG_FILE);
-    read_config(&path)
+/// Reads the optional system-wide configuration file and the user's configuration file in
+/// `path`, in increasing order of precedence
+pub async fn read_config_layers(path: impl AsRef<Path>) -> Result<Vec<(PathBuf, String)>> {
+    let system = Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE);
+    let user = path.as_ref().join(CONFIG_FILE);
+
+    let mut layers = Vec::new();
+    match fs::read_to_string(&system).await {
+        Ok(contents) => layers.push((system, contents)),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => Err(e)
+            .with_context(|| system.display().to_string())
+            .context("Unable to read system-wide configuration file")?,
+    }
+
+    let contents = fs::read_to_string(&user)
         .await
-        .with_context(|| format!("{}", path.display()))
-        .context(CONFIG_HELP)
+        .with_context(|| user.display().to_string())
+        .context(CONFIG_HELP)?;
+    layers.push((user, contents));
+    Ok(layers)
+}
+
+/// Parses configuration layers in increasing order of precedence. Tables, such as the
+/// browsers and their settings, are merged key by key, other values are replaced.
+pub fn parse_config_layers(layers: &[(PathBuf, String)]) -> Result<Config> {
+    let mut merged = toml::Table::new();
+    for (path, contents) in layers {
+        let layer = toml::from_str(contents).with_context(|| path.display().to_string())?;
+        merge_tables(&mut merged, layer);
+    }
+
+    // Usually the file was created on the first run, but not filled in
+    if merged.is_empty() {
+        let files = layers.iter().map(|(p, _)| p.display().to_string());
+        bail!(
+            "{}: the configuration file is empty, fill it in as shown above",
+            files.collect::<Vec<_>>().join(", ")
+        );
+    }
+
+    let files = layers
+        .iter()
+        .map(|(p, _)| p.display().to_string())
+        .collect::<Vec<_>>();
+    toml::Value::Table(merged)
+        .try_into()
+        .with_context(|| files.join(" merged with "))
+}
+
+fn merge_tables(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(b)), toml::Value::Table(l)) => merge_tables(b, l),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Resolves a bare binary name against `PATH` like `which`, other paths are returned as-is
+fn resolve_in_path(binary: &Path) -> Result<PathBuf> {
+    if binary.components().count() != 1 || binary.is_absolute() {
+        return Ok(binary.into());
+    }
+
+    env::var_os("PATH")
+        .iter()
+        .flat_map(env::split_paths)
+        .map(|dir| dir.join(binary))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        })
+        .ok_or_else(|| {
+            anyhow!(
+                "{} not found in PATH, configure proxy_client with the full path instead",
+                binary.display()
+            )
+        })
+}
+
+pub fn load_config(layers: &[(PathBuf, String)]) -> Result<Config> {
+    let mut config = parse_config_layers(layers).context(CONFIG_HELP)?;
+
+    config.daemon.proxy_client = resolve_in_path(&config.daemon.proxy_client)
+        .context("Unable to locate the proxy client")?;
+    for (name, browser) in &mut config.browsers {
+        if let Some(client) = &browser.proxy_client {
+            browser.proxy_client =
+                Some(resolve_in_path(client).with_context(|| {
+                    format!("Unable to locate the proxy client of browser {name}")
+                })?);
+        }
+
+        for pointer in browser.manifest_overrides.keys() {
+            manifest::override_target(pointer)
+                .with_context(|| format!("Invalid manifest_overrides of browser {name}"))?;
+        }
+
+        // systemd rejects such names in FileDescriptorName=
+        if let Some(socket) = &browser.socket_name {
+            if socket.is_empty() || socket.contains(':') || !socket.is_ascii() {
+                bail!("Invalid socket_name of browser {name}: {socket:?}");
+            }
+        }
+    }
+
+    let mut socket_names = HashMap::new();
+    for (name, browser) in config.enabled_browsers() {
+        let socket = browser.socket_name.as_ref().unwrap_or(name);
+        if let Some(other) = socket_names.insert(socket, name) {
+            let mut browsers = [name, other];
+            browsers.sort();
+            bail!(
+                "Browsers {} and {} both use the socket named {socket}",
+                browsers[0],
+                browsers[1]
+            );
+        }
+    }
+    Ok(config)
 }
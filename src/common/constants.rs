@@ -3,8 +3,29 @@
 
 pub const SOCKET_PREFIX: &str = "nm-proxy-";
 pub const SOCKET_SUFFIX: &str = ".socket";
+#[cfg(target_os = "linux")]
+pub const MAX_SOCKET_PATH: usize = 107; // Size of sun_path, minus the terminating NUL
+#[cfg(not(target_os = "linux"))]
+pub const MAX_SOCKET_PATH: usize = 103;
 pub const CONFIG_DIR: &str = "nm-proxy";
 pub const CONFIG_FILE: &str = "config.toml";
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/nm-proxy"; // Defaults layered under the user's config
 pub const APP_MANIFEST_DIR: &str = "manifest";
+#[cfg(not(target_os = "macos"))]
+pub const NMH_BASE_DIR: &str = "~/.var/app"; // Flatpak app directories, joined with the app ID
+#[cfg(target_os = "macos")]
+pub const NMH_BASE_DIR: &str = "~/Library/Application Support";
+pub const NMH_BASE_DIR_ENV: &str = "NM_PROXY_FLATPAK_BASE"; // Overrides NMH_BASE_DIR
 pub const PROXY_CLIENT_BIN: &str = "nm-proxy-client";
 pub const SETTINGS_FILE_NAME: &str = "nm-proxy-settings.toml";
+pub const SETTINGS_FILE_ENV: &str = "NM_PROXY_SETTINGS_FILE"; // Overrides SETTINGS_FILE_NAME
+pub const CLIENT_TIMEOUT_ENV: &str = "NM_PROXY_CLIENT_TIMEOUT"; // Session limit in seconds
+pub const CLIENT_TIMEOUT_EXIT_CODE: i32 = 124; // Same as timeout(1)
+pub const CLIENT_RECONNECT_ENV: &str = "NM_PROXY_CLIENT_RECONNECT"; // Window in seconds
+pub const PROFILE_ENV: &str = "NM_PROXY_PROFILE"; // Browser profile, from the client to native binaries
+pub const EXTENSION_ID_ENV: &str = "NM_PROXY_EXTENSION_ID"; // Set for native binaries if enabled
+pub const SETUP_LOCK_FILE_NAME: &str = "nm-proxy-setup.lock";
+pub const DEPLOYMENT_INDEX_FILE_NAME: &str = "nm-proxy-deployment.json";
+pub const MAX_MESSAGE_SIZE: u32 = u32::MAX; // 4 GiB, the most the length prefix allows
+pub const MAX_OBJECT_SIZE: u32 = 16 * 1024 * 1024; // Handshakes and other objects read whole
+pub const PROTOCOL_VERSION: u32 = 1; // Client-daemon protocol, 0 denotes legacy clients
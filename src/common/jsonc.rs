@@ -0,0 +1,57 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/// Blanks out `//` and `/* */` comments outside of string literals. Comments are replaced
+/// byte for byte by spaces, keeping newlines, so that serde error positions still match the
+/// source file.
+pub fn strip_comments(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let blank = |output: &mut String, c: char| match c {
+        '\n' => output.push(c),
+        _ => output.extend(std::iter::repeat_n(' ', c.len_utf8())),
+    };
+    let mut chars = input.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            output.push(c);
+            match c {
+                '\\' => output.extend(chars.next()), // Keep escaped characters verbatim
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                output.push(c);
+            }
+            ('/', Some('/')) => {
+                // Line comment, blank until (but not including) the newline
+                blank(&mut output, c);
+                while let Some(c) = chars.next_if(|&c| c != '\n') {
+                    blank(&mut output, c);
+                }
+            }
+            ('/', Some('*')) => {
+                // Block comment, blank until the closing delimiter
+                blank(&mut output, c);
+                chars.next().inspect(|&c| blank(&mut output, c));
+                let mut prev = '\0';
+                for c in chars.by_ref() {
+                    blank(&mut output, c);
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
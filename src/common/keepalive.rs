@@ -0,0 +1,181 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::latency::LatencyHistogram;
+use crate::common::{forward_frame_body, read_frame_length, FrameCodec};
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex as StdMutex;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::time::{self, Duration, Instant};
+
+/// Length prefixes with this bit set denote control frames, which are exchanged between
+/// the proxy client and daemon only
+pub const CONTROL_FLAG: u32 = 1 << 31;
+/// Largest data frame on links carrying control frames, so that its length prefix never
+/// has `CONTROL_FLAG` set
+pub const MAX_LINK_FRAME_SIZE: u32 = CONTROL_FLAG - 1;
+const PING: u32 = CONTROL_FLAG | 1;
+const PONG: u32 = CONTROL_FLAG | 2;
+/// Sent by the daemon before closing a session that ended, rather than broke, to reconnecting clients
+pub const SESSION_END: u32 = CONTROL_FLAG | 3;
+/// Sent by the proxy client when it is terminated, for the native binary to be stopped right away
+pub const TERMINATE: u32 = CONTROL_FLAG | 4;
+
+/// Keepalive intervals without receiving anything after which the peer is considered gone
+const KEEPALIVE_TIMEOUT_INTERVALS: u32 = 3;
+
+/// Framed connection between the proxy client and daemon, whose writing half is shared
+/// between forwarded data and control frames
+pub struct Link<W> {
+    writer: Mutex<W>,
+    last_seen: StdMutex<Instant>,
+    /// The peer marked its termination
+    terminated: AtomicBool,
+    /// Latencies of frames forwarded into and out of the link, if measured
+    latency: Option<(LatencyHistogram, LatencyHistogram)>,
+}
+
+impl<W: AsyncWrite + Unpin> Link<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+            last_seen: StdMutex::new(Instant::now()),
+            terminated: AtomicBool::new(false),
+            latency: None,
+        }
+    }
+
+    /// Measures the time from receiving the length prefix of each data frame to having
+    /// written all of it, in both directions described by `directions` (into the link,
+    /// out of the link). Frames slower than `threshold` are logged.
+    pub fn with_latency(mut self, directions: [&'static str; 2], threshold: Duration) -> Self {
+        self.latency = Some((
+            LatencyHistogram::new(directions[0], threshold),
+            LatencyHistogram::new(directions[1], threshold),
+        ));
+        self
+    }
+
+    /// Latency histograms of frames forwarded into and out of the link, if measured
+    pub fn latency(&self) -> Option<&(LatencyHistogram, LatencyHistogram)> {
+        self.latency.as_ref()
+    }
+
+    /// Exclusive access to the writing half, e.g. for forwarding a frame
+    pub async fn writer(&self) -> MutexGuard<'_, W> {
+        self.writer.lock().await
+    }
+
+    async fn send_control(&self, frame: u32) -> std::io::Result<()> {
+        self.writer().await.write_all(&frame.to_ne_bytes()).await
+    }
+
+    /// Marks the end of the session for a reconnecting proxy client, after the last frame
+    pub async fn send_session_end(&self) -> std::io::Result<()> {
+        self.send_control(SESSION_END).await
+    }
+
+    /// Marks the termination of the proxy client, after the frame currently being forwarded
+    pub async fn send_terminate(&self) -> std::io::Result<()> {
+        self.send_control(TERMINATE).await
+    }
+
+    /// Whether the peer marked its termination, which ends `forward_to` like EOF
+    pub fn terminated(&self) -> bool {
+        self.terminated.load(Ordering::Relaxed)
+    }
+
+    /// Forwards frames from `reader` over the link until EOF, returning the number of
+    /// uncompressed bytes forwarded
+    pub async fn forward_from(
+        &self,
+        reader: &mut (impl AsyncRead + Unpin),
+        (max_size, codec): (u32, FrameCodec),
+    ) -> std::io::Result<u64> {
+        let mut total = 0;
+        while let Some(n) = self.forward_frame_from(reader, (max_size, codec)).await? {
+            total += n;
+        }
+
+        Ok(total)
+    }
+
+    /// Forwards a single frame from `reader` over the link, returning the number of
+    /// uncompressed bytes forwarded or `None` if `reader` was closed before the frame started
+    pub async fn forward_frame_from(
+        &self,
+        reader: &mut (impl AsyncRead + Unpin),
+        (max_size, codec): (u32, FrameCodec),
+    ) -> std::io::Result<Option<u64>> {
+        let Some(length) = read_frame_length(reader).await? else {
+            return Ok(None);
+        };
+
+        let started = Instant::now();
+        let mut writer = self.writer().await;
+        let n = forward_frame_body(length, reader, &mut *writer, max_size, codec).await?;
+        if let Some((into_link, _)) = &self.latency {
+            into_link.record(started.elapsed(), n);
+        }
+        Ok(Some(n))
+    }
+
+    /// Forwards frames received over the link from `reader` to `writer` until EOF or the
+    /// peer terminates while answering control frames, returning the number of uncompressed bytes forwarded
+    pub async fn forward_to(
+        &self,
+        reader: &mut (impl AsyncRead + Unpin),
+        writer: &mut (impl AsyncWrite + Unpin),
+        (max_size, codec): (u32, FrameCodec),
+    ) -> std::io::Result<u64> {
+        let mut total = 0;
+        while let Some(length) = read_frame_length(reader).await? {
+            *self.last_seen.lock().unwrap() = Instant::now();
+            match length {
+                PING => self.send_control(PONG).await?,
+                PONG => (),
+                TERMINATE => {
+                    self.terminated.store(true, Ordering::Relaxed);
+                    break;
+                }
+                l if l & CONTROL_FLAG != 0 => {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("Unknown control frame {l:#x}"),
+                    ));
+                }
+                l => {
+                    let started = Instant::now();
+                    let n = forward_frame_body(l, reader, writer, max_size, codec).await?;
+                    if let Some((_, out_of_link)) = &self.latency {
+                        out_of_link.record(started.elapsed(), n);
+                    }
+                    total += n;
+                }
+            }
+        }
+
+        Ok(total)
+    }
+
+    /// Pings the peer every `interval`, failing if nothing has been received over the
+    /// link for `KEEPALIVE_TIMEOUT_INTERVALS` intervals. Requires `forward_to` to run.
+    pub async fn keepalive(&self, interval: Duration) -> std::io::Result<()> {
+        let timeout = interval * KEEPALIVE_TIMEOUT_INTERVALS;
+        loop {
+            time::sleep(interval).await;
+
+            let idle = self.last_seen.lock().unwrap().elapsed();
+            if idle >= timeout {
+                return Err(IoError::new(
+                    ErrorKind::TimedOut,
+                    format!("No keepalive response in {idle:.1?}, the peer seems to be gone"),
+                ));
+            }
+
+            self.send_control(PING).await?;
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::Mutex;
+use tokio::time::Duration;
+use tracing::info;
+
+/// Power-of-two microsecond buckets, the last one collects everything above ~36 minutes
+const BUCKETS: usize = 32;
+
+/// Histogram of message forwarding latencies in one direction of a link
+pub struct LatencyHistogram {
+    direction: &'static str,
+    threshold: Duration,
+    inner: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u64; BUCKETS],
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    /// Messages forwarded in `direction` that take longer than `threshold` are logged
+    pub fn new(direction: &'static str, threshold: Duration) -> Self {
+        Self {
+            direction,
+            threshold,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration, bytes: u64) {
+        if elapsed > self.threshold {
+            info!(
+                "forwarding a message of {bytes} bytes {} took {elapsed:.1?}",
+                self.direction
+            );
+        }
+
+        let micros = elapsed.as_micros().max(1);
+        let bucket = (micros.ilog2() as usize).min(BUCKETS - 1);
+        let mut inner = self.inner.lock().unwrap();
+        inner.counts[bucket] += 1;
+        inner.total += elapsed;
+        inner.max = inner.max.max(elapsed);
+    }
+
+    /// Summary of the recorded latencies, percentiles are upper bounds of their buckets
+    pub fn summary(&self) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        let count: u64 = inner.counts.iter().sum();
+        if count == 0 {
+            return None;
+        }
+
+        let percentile = |p: u64| {
+            let mut seen = 0;
+            let bucket = inner.counts.iter().position(|c| {
+                seen += c;
+                seen * 100 >= count * p
+            });
+            let upper = Duration::from_micros(2 << bucket.unwrap_or(BUCKETS - 1));
+            upper.min(inner.max)
+        };
+
+        Some(format!(
+            "{count} messages {}: mean {:.1?}, 50% within {:.1?}, 99% within {:.1?}, max {:.1?}",
+            self.direction,
+            inner.total / count as u32,
+            percentile(50),
+            percentile(99),
+            inner.max,
+        ))
+    }
+}
@@ -0,0 +1,57 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::common::runtime::LogLevel;
+
+/// Handle for replacing the default level of the log filter once the configuration is known
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+    forced: bool,
+}
+
+impl LogFilter {
+    /// Creates the filter layer, which logs at `default` unless RUST_LOG is set
+    pub fn new(default: LevelFilter) -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(env_filter(default));
+        (
+            layer,
+            Self {
+                handle,
+                forced: false,
+            },
+        )
+    }
+
+    /// Creates a filter layer logging at `level` regardless of RUST_LOG and the configuration
+    pub fn forced(level: LevelFilter) -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(EnvFilter::default().add_directive(level.into()));
+        (
+            layer,
+            Self {
+                handle,
+                forced: true,
+            },
+        )
+    }
+
+    /// Replaces the default level with the configured `level`, RUST_LOG still takes precedence
+    pub fn configure(&self, level: Option<LogLevel>) {
+        let Some(level) = level.filter(|_| !self.forced) else {
+            return;
+        };
+
+        if let Err(e) = self.handle.reload(env_filter(level.into())) {
+            eprintln!("Failed to apply the configured log level: {e}");
+        }
+    }
+}
+
+/// Filter ignoring malformed RUST_LOG directives
+fn env_filter(default: LevelFilter) -> EnvFilter {
+    EnvFilter::builder()
+        .with_default_directive(default.into())
+        .from_env_lossy()
+}
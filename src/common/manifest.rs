@@ -0,0 +1,175 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::jsonc;
+use crate::common::traits::*;
+use anyhow::{anyhow, bail, Error, Result};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// Parse an app manifest, optionally stripping comments first. A leading UTF-8 byte order
+/// mark, as written by some Windows tools, is ignored. CRLF line endings are whitespace to JSON.
+pub fn parse_manifest(contents: &str, allow_comments: bool) -> Result<Value> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let contents: Cow<str> = match allow_comments {
+        true => jsonc::strip_comments(contents).into(),
+        false => contents.into(),
+    };
+
+    serde_json::from_str(&contents).map_err(|e| {
+        let location = format!("line {}, column {}", e.line(), e.column());
+        Error::from(e).context(location)
+    })
+}
+
+/// File name under which browsers look up the app manifest, i.e., `<name>.json`
+pub fn manifest_file_name(manifest: &Value) -> Option<String> {
+    match &manifest["name"] {
+        Value::String(s) => Some(format!("{s}.json")),
+        _ => None,
+    }
+}
+
+/// Whether `name` follows the native messaging host name grammar: segments of lowercase
+/// letters, digits and underscores, joined by single dots. Such names are safe file names.
+pub fn valid_manifest_name(name: &str) -> bool {
+    name.split('.').all(|segment| {
+        !segment.is_empty()
+            && (segment.bytes()).all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    })
+}
+
+/// App manifest rewritten to launch the proxy client
+#[derive(Debug)]
+pub struct ProxiedManifest {
+    /// File name that browsers look the manifest up by
+    pub file_name: String,
+    /// Native binary that the daemon launches in place of the proxy client
+    pub binary: String,
+    pub manifest: Value,
+    /// Problems that don't prevent deploying the manifest
+    pub warnings: Vec<String>,
+}
+
+/// Looks up a string field of an app manifest, telling a missing key apart from a wrong type
+fn string_field<'a>(manifest: &'a Value, key: &str) -> Result<&'a str> {
+    let found = match manifest.get(key) {
+        Some(Value::String(s)) => return Ok(s),
+        None => bail!("Malformed app manifest, {key:?} key missing"),
+        Some(Value::Null) => "null",
+        Some(Value::Bool(_)) => "a boolean",
+        Some(Value::Number(_)) => "a number",
+        Some(Value::Array(_)) => "an array",
+        Some(Value::Object(_)) => "an object",
+    };
+    bail!("Malformed app manifest, {key:?} must be a string, found {found}")
+}
+
+/// Validates an app manifest read from `file_name` and points it at `client_path`. The native
+/// binary is replaced with the one `binary_override` returns for the deployed file name, if any.
+pub fn proxy_manifest(
+    mut manifest: Value,
+    file_name: &str,
+    binary_override: impl FnOnce(&str) -> Option<PathBuf>,
+    client_path: &Path,
+) -> Result<ProxiedManifest> {
+    let mut warnings = Vec::new();
+
+    // Extract the "path" field
+    let mut binary = string_field(&manifest, "path")?.to_owned();
+
+    // Browsers look up manifests by their "name", deploy under that for the handshake to match
+    if let Some(Value::String(name)) = manifest.get("name") {
+        if !valid_manifest_name(name) {
+            bail!(
+                "Invalid app manifest name {name:?}, only lowercase letters, digits and \
+                underscores joined by dots are allowed"
+            );
+        }
+    }
+    let file_name = match manifest_file_name(&manifest) {
+        Some(n) if n == file_name => n,
+        Some(n) => {
+            warnings.push(format!(
+                "manifest name does not match file name {file_name}, deploying as {n}"
+            ));
+            n
+        }
+        None => {
+            warnings.push(format!(
+                "manifest \"name\" key missing, deploying as {file_name}"
+            ));
+            file_name.into()
+        }
+    };
+
+    // A configured override takes precedence over the manifest-provided binary
+    if let Some(path) = binary_override(&file_name) {
+        binary = path.into_string_result()?;
+    }
+
+    // Check that the "type" field is "stdio" (other formats are currently unsupported)
+    match string_field(&manifest, "type")? {
+        "stdio" => (),
+        t => {
+            bail!("Unsupported app manifest type {t:?}, only type \"stdio\" is currently supported")
+        }
+    }
+
+    // Replace the path with the proxy client path
+    manifest["path"] = client_path.to_string_result()?.into();
+
+    Ok(ProxiedManifest {
+        file_name,
+        binary,
+        manifest,
+        warnings,
+    })
+}
+
+/// Keys that setup validates and rewrites itself, which can't be overridden
+const MANAGED_KEYS: [&str; 3] = ["name", "path", "type"];
+
+/// Splits the JSON pointer of a manifest override into the pointer to its parent and the
+/// unescaped last key, rejecting pointers into the keys managed by setup
+pub fn override_target(pointer: &str) -> Result<(&str, String)> {
+    let unescape = |key: &str| key.replace("~1", "/").replace("~0", "~");
+    let Some((parent, key)) = pointer
+        .rsplit_once('/')
+        .filter(|_| pointer.starts_with('/'))
+    else {
+        bail!("Invalid JSON pointer {pointer:?}, it must start with /");
+    };
+
+    let top = pointer[1..].split('/').next().map(unescape);
+    if top.is_some_and(|k| MANAGED_KEYS.contains(&k.as_str())) {
+        bail!("{pointer:?} is managed by nm-proxy and can't be overridden");
+    }
+    Ok((parent, unescape(key)))
+}
+
+/// Sets the values that `overrides` address by JSON pointer, e.g. `/description`, in an app
+/// manifest. Missing keys are added to objects and `-` appends to an array.
+pub fn override_values<'a>(
+    manifest: &mut Value,
+    overrides: impl IntoIterator<Item = (&'a String, &'a Value)>,
+) -> Result<()> {
+    for (pointer, value) in overrides {
+        let (parent, key) = override_target(pointer)?;
+        match manifest.pointer_mut(parent) {
+            Some(Value::Object(object)) => {
+                object.insert(key, value.clone());
+            }
+            Some(Value::Array(array)) if key == "-" => array.push(value.clone()),
+            Some(Value::Array(array)) => {
+                let element = key.parse().ok().and_then(|i: usize| array.get_mut(i));
+                *element.ok_or_else(|| anyhow!("{pointer:?} is out of bounds"))? = value.clone();
+            }
+            Some(_) => bail!("{pointer:?} doesn't address a member of an object or array"),
+            None => bail!("Parent of {pointer:?} not found in the app manifest"),
+        }
+    }
+
+    Ok(())
+}
@@ -4,52 +4,172 @@
 use std::env;
 use std::env::VarError;
 use std::io::Error as IoError;
-use std::io::{ErrorKind, IoSlice};
+use std::io::ErrorKind;
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use byteorder::ByteOrder;
 use byteorder::NativeEndian;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{copy, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::common::constants::*;
 
 pub mod config;
 pub mod constants;
+mod jsonc;
+pub mod keepalive;
+pub mod latency;
+pub mod logging;
+pub mod manifest;
 pub mod runtime;
 pub mod traits;
 
-#[derive(Serialize, Deserialize)]
+/// Sent by the proxy client to open a session. Fields added after the original handshake are
+/// omitted at their defaults, daemons predating one close the connection on seeing it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(deny_unknown_fields)] // Strict mode
 pub struct HandshakeMessage {
     pub manifest_name: String,
     pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "is_legacy")] // Legacy clients don't negotiate
+    pub protocol_version: u32,
+    #[serde(
+        default = "default_max_message_size",
+        skip_serializing_if = "is_default_max_message_size"
+    )]
+    pub max_message_size: u32,
+    /// Offer lz4 compression of frame bodies
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compression: bool,
+    /// Offer answering keepalive pings
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub keepalive: bool,
+    /// Release of the proxy client, unknown for older clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_version: Option<String>,
+    /// Ask for the end of the session to be marked, so that the proxy client can reconnect
+    /// when the connection breaks without it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reconnect: bool,
+    /// Offer telling the daemon when the proxy client is terminated
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub terminate: bool,
+    /// Browser profile hinted by the environment of the proxy client, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
 }
 
-pub fn parse_env(name: &str, default: Option<&str>) -> Result<String> {
-    let result = env::var(name);
-    if let (Err(VarError::NotPresent), Some(value)) = (&result, default) {
-        return Ok(value.into());
+impl HandshakeMessage {
+    /// Handshake of the same session for daemons predating negotiation, which forward
+    /// everything as is
+    pub fn legacy(&self) -> Self {
+        Self {
+            manifest_name: self.manifest_name.clone(),
+            args: self.args.clone(),
+            protocol_version: 0,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        }
     }
+}
+
+/// Response of the daemon to a handshake from a client with protocol version 1 or later
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)] // Strict mode
+pub struct HandshakeReply {
+    pub protocol_version: u32,
+    pub max_message_size: u32,
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Frame bodies are lz4 compressed after the reply, omitted when false for older clients
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compression: bool,
+    /// Seconds between keepalive pings in both directions, if enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval: Option<u64>,
+    /// The end of the session is marked with a control frame, omitted when false for older clients
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reconnect: bool,
+    /// The proxy client may mark its termination with a control frame, omitted when false for
+    /// older clients
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub terminate: bool,
+}
+
+impl HandshakeReply {
+    /// Largest data frame of the session, capped below `CONTROL_FLAG` if control frames are
+    /// exchanged. Daemons predating the cap may reply with larger sizes.
+    pub fn max_frame_size(&self) -> u32 {
+        match self.keepalive_interval.is_some() || self.terminate || self.reconnect {
+            true => self.max_message_size.min(keepalive::MAX_LINK_FRAME_SIZE),
+            false => self.max_message_size,
+        }
+    }
+}
+
+fn default_max_message_size() -> u32 {
+    MAX_MESSAGE_SIZE
+}
+
+fn is_default_max_message_size(size: &u32) -> bool {
+    *size == MAX_MESSAGE_SIZE
+}
+
+fn is_legacy(protocol_version: &u32) -> bool {
+    *protocol_version == 0
+}
+
+/// File name of the socket of the given browser in the runtime directory
+pub fn socket_file_name(browser: &str) -> String {
+    format!("{SOCKET_PREFIX}{browser}{SOCKET_SUFFIX}")
+}
 
-    result.with_context(|| format!("Failed to parse environment variable {}", name))
+pub fn parse_env(name: &str, default: Option<&str>) -> Result<String> {
+    match (env::var(name), default) {
+        (Ok(value), _) => Ok(value),
+        (Err(VarError::NotPresent), Some(value)) => Ok(value.into()),
+        (Err(VarError::NotPresent), None) => Err(anyhow!(
+            "Environment variable {name} is not set, ensure it is exported to this process"
+        )),
+        (Err(VarError::NotUnicode(value)), _) => Err(anyhow!(
+            "Environment variable {name} contains invalid UTF-8: {}, only UTF-8 values are supported",
+            value.to_string_lossy()
+        )),
+    }
 }
 
 pub async fn send_nm_object(
     writer: &mut (impl AsyncWrite + Unpin),
     object: impl Serialize,
+) -> Result<()> {
+    send_nm_object_with::<NativeEndian>(writer, object).await
+}
+
+/// Sends a native messaging object with its length prefix in byte order `B`
+pub async fn send_nm_object_with<B: ByteOrder>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    object: impl Serialize,
 ) -> Result<()> {
     let data = serde_json::to_vec(&object).context("Serializing object failed")?;
 
-    let mut len_buf = vec![0u8; std::mem::size_of::<u32>()];
-    NativeEndian::write_u32(
-        len_buf.as_mut_slice(),
+    let mut frame = vec![0u8; std::mem::size_of::<u32>()];
+    B::write_u32(
+        frame.as_mut_slice(),
         data.len()
             .try_into()
             .context("Attempted to send message message larger than 4 GiB")?,
     );
+    frame.extend_from_slice(&data);
 
+    // A single vectored write may be partial, large messages need multiple writes
     writer
-        .write_vectored(&[IoSlice::new(&len_buf), IoSlice::new(&data)])
+        .write_all(&frame)
         .await
         .context("Failed to write message")?;
 
@@ -58,6 +178,14 @@ pub async fn send_nm_object(
 
 pub async fn recv_nm_object<T: DeserializeOwned>(
     reader: &mut (impl AsyncRead + Unpin),
+) -> Result<T> {
+    recv_nm_object_with::<NativeEndian, T>(reader).await
+}
+
+/// Receives a native messaging object of at most `MAX_OBJECT_SIZE` bytes with its length
+/// prefix in byte order `B`
+pub async fn recv_nm_object_with<B: ByteOrder, T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
 ) -> Result<T> {
     let mut len_buf = vec![0; std::mem::size_of::<u32>()];
     reader
@@ -65,7 +193,18 @@ pub async fn recv_nm_object<T: DeserializeOwned>(
         .await
         .context("Failed to read message length")?;
 
-    let length: usize = NativeEndian::read_u32(&len_buf)
+    let length = B::read_u32(&len_buf);
+    if length > MAX_OBJECT_SIZE {
+        let error = anyhow!("Message length {length} exceeds maximum of {MAX_OBJECT_SIZE} bytes");
+        if length.swap_bytes() <= MAX_OBJECT_SIZE {
+            // A reasonable byte-swapped length hints at a framing endianness mismatch
+            return Err(error.context("Length looks byte-swapped, peer endianness mismatch?"));
+        }
+
+        return Err(error);
+    }
+
+    let length: usize = length
         .try_into()
         .map_err(|err| IoError::new(ErrorKind::InvalidData, err))
         .context("Failed to parse message length")?;
@@ -78,3 +217,149 @@ pub async fn recv_nm_object<T: DeserializeOwned>(
 
     Ok(serde_json::from_slice(&buffer)?)
 }
+
+/// Transformation applied to frame bodies while forwarding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameCodec {
+    /// Forward bodies unchanged
+    Plain,
+    /// Compress bodies with lz4 before writing them
+    Compress,
+    /// Decompress lz4 bodies before writing them
+    Decompress,
+}
+
+impl FrameCodec {
+    /// Largest frame body this codec accepts for messages of at most `max_size` bytes
+    fn max_input_size(self, max_size: u32) -> u64 {
+        match self {
+            FrameCodec::Decompress => lz4_flex::block::get_maximum_output_size(max_size as usize)
+                .saturating_add(std::mem::size_of::<u32>())
+                as u64,
+            _ => max_size.into(),
+        }
+    }
+
+    /// Transforms a buffered frame body, returning it and the uncompressed length
+    fn apply(self, body: Vec<u8>, max_size: u32) -> std::io::Result<(Vec<u8>, usize)> {
+        match self {
+            FrameCodec::Plain => {
+                let len = body.len();
+                Ok((body, len))
+            }
+            FrameCodec::Compress => {
+                let len = body.len();
+                Ok((lz4_flex::compress_prepend_size(&body), len))
+            }
+            FrameCodec::Decompress => {
+                let invalid = |e| IoError::new(ErrorKind::InvalidData, e);
+                let (length, data) = lz4_flex::block::uncompressed_size(&body).map_err(invalid)?;
+                if length > max_size as usize {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("Message length {length} exceeds maximum of {max_size} bytes"),
+                    ));
+                }
+
+                let body = lz4_flex::decompress(data, length).map_err(invalid)?;
+                Ok((body, length))
+            }
+        }
+    }
+}
+
+/// Forwards native messaging frames from `reader` to `writer` until EOF, rejecting
+/// messages larger than `max_size`. Returns the number of bytes forwarded.
+pub async fn forward_nm_frames(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    max_size: u32,
+) -> std::io::Result<u64> {
+    forward_nm_frames_with(reader, writer, max_size, FrameCodec::Plain).await
+}
+
+/// Like `forward_nm_frames`, but transforms frame bodies with `codec`. Returns the
+/// number of uncompressed bytes forwarded.
+pub async fn forward_nm_frames_with(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    max_size: u32,
+    codec: FrameCodec,
+) -> std::io::Result<u64> {
+    let mut total = 0;
+    while let Some(n) = forward_nm_frame(reader, writer, max_size, codec).await? {
+        total += n;
+    }
+
+    Ok(total)
+}
+
+/// Forwards a single native messaging frame, returning the number of uncompressed
+/// bytes forwarded or `None` if `reader` was closed before the frame started
+pub async fn forward_nm_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    max_size: u32,
+    codec: FrameCodec,
+) -> std::io::Result<Option<u64>> {
+    match read_frame_length(reader).await? {
+        Some(length) => forward_frame_body(length, reader, writer, max_size, codec)
+            .await
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads the length prefix of the next frame, returning `None` if `reader` was closed
+pub async fn read_frame_length(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> std::io::Result<Option<u32>> {
+    let mut len_buf = [0u8; std::mem::size_of::<u32>()];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => Ok(Some(NativeEndian::read_u32(&len_buf))),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None), // Closed
+        Err(e) => Err(e),
+    }
+}
+
+/// Forwards the body of a frame whose `length` prefix was already read from `reader`,
+/// returning the number of uncompressed bytes forwarded
+pub async fn forward_frame_body(
+    length: u32,
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    max_size: u32,
+    codec: FrameCodec,
+) -> std::io::Result<u64> {
+    if u64::from(length) > codec.max_input_size(max_size) {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!("Message length {length} exceeds maximum of {max_size} bytes"),
+        ));
+    }
+
+    let mut len_buf = [0u8; std::mem::size_of::<u32>()];
+    let n = if codec == FrameCodec::Plain {
+        // Stream the body through without buffering it
+        NativeEndian::write_u32(&mut len_buf, length);
+        writer.write_all(&len_buf).await?;
+        let n = copy(&mut (&mut *reader).take(length.into()), writer).await?;
+        if n < length.into() {
+            return Err(ErrorKind::UnexpectedEof.into()); // Truncated message
+        }
+
+        n
+    } else {
+        let mut body = vec![0; length as usize];
+        reader.read_exact(&mut body).await?;
+        let (body, n) = codec.apply(body, max_size)?;
+
+        // Both body lengths are bounded by the checks above
+        NativeEndian::write_u32(&mut len_buf, body.len() as u32);
+        writer.write_all(&len_buf).await?;
+        writer.write_all(&body).await?;
+        n as u64
+    };
+
+    Ok((len_buf.len() as u64) + n)
+}
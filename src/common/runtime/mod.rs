@@ -1,8 +1,14 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use anyhow::{anyhow, bail, Result};
+use crate::common;
+use crate::common::constants::*;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use nix::unistd::{access, getuid, AccessFlags};
 use std::env;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 
 pub mod settings;
 pub use settings::*;
@@ -19,3 +25,60 @@ pub async fn parse_runtime_dir(context: &str) -> Result<String> {
 
     bail!("Usage: {} <runtime-dir>\n{}", invocation_path, context);
 }
+
+/// Points out a full runtime directory as the cause of an IO error, if it ran out of space
+pub fn full_dir_context(e: std::io::Error, dir: impl AsRef<Path>) -> Error {
+    let full = e.kind() == ErrorKind::StorageFull;
+    let e = Error::from(e);
+    match full {
+        true => e.context(format!(
+            "The runtime directory {} seems to be full. It is usually a small tmpfs, \
+            free up space in it, e.g. by removing stale files, and try again",
+            dir.as_ref().display()
+        )),
+        false => e,
+    }
+}
+
+/// Verifies that the socket of `browser` in the runtime directory fits into a Unix socket
+/// address, the daemon would otherwise fail to bind it with an unhelpful error
+pub fn check_socket_path(dir: impl AsRef<Path>, browser: &str) -> Result<()> {
+    let path = dir.as_ref().join(common::socket_file_name(browser));
+    let length = path.as_os_str().len();
+    if length > MAX_SOCKET_PATH {
+        bail!(
+            "Socket path {} is {length} bytes long, exceeding the limit of {MAX_SOCKET_PATH} \
+            bytes for Unix sockets. Use a shorter browser name or runtime directory",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Verifies that the runtime directory is a directory owned and writable by the current user
+pub fn check_runtime_dir(dir: impl AsRef<Path>) -> Result<()> {
+    let dir = dir.as_ref();
+    let context = || {
+        format!(
+            "Invalid runtime directory {}. XDG_RUNTIME_DIR should point to a writable \
+            directory owned by the current user, usually created at login by pam_systemd",
+            dir.display()
+        )
+    };
+
+    let metadata = dir.metadata().with_context(context)?;
+    if !metadata.is_dir() {
+        return Err(anyhow!("Not a directory")).with_context(context);
+    }
+
+    let uid = getuid().as_raw();
+    if metadata.uid() != uid {
+        return Err(anyhow!("Owned by UID {} instead of {uid}", metadata.uid()))
+            .with_context(context);
+    }
+
+    access(dir, AccessFlags::W_OK | AccessFlags::X_OK)
+        .context("Not writable, is it mounted read-only?")
+        .with_context(context)
+}
@@ -1,42 +1,267 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use crate::common;
 use crate::common::constants::*;
 use anyhow::Result;
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
+use expanduser::expanduser;
 use serde::Deserialize;
 use serde::Serialize;
 use std::collections::HashMap;
-use std::path::Path;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tracing::instrument;
+use tracing::level_filters::LevelFilter;
 
 pub type NativeBinaryMap = HashMap<String, HashMap<String, String>>;
 
+/// Verbosity of an individual log message, configurable by the user
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// Handling of the native binary's stderr, written as `log`, `inherit`, `null` or `file:<path>`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum StderrMode {
+    /// Log lines through the daemon, see `stderr_warn_lines` (default)
+    #[default]
+    Log,
+    /// Share the daemon's own stderr
+    Inherit,
+    /// Discard the output
+    Null,
+    /// Append the output to a file
+    File(PathBuf),
+}
+
+impl TryFrom<String> for StderrMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "log" => Ok(Self::Log),
+            "inherit" => Ok(Self::Inherit),
+            "null" => Ok(Self::Null),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => expanduser(path)
+                    .map(Self::File)
+                    .map_err(|e| format!("stderr file path expansion failed: {e}")),
+                _ => Err(format!(
+                    "invalid stderr mode \"{s}\", expected \"log\", \"inherit\", \"null\" or \"file:<path>\""
+                )),
+            },
+        }
+    }
+}
+
+impl From<StderrMode> for String {
+    fn from(mode: StderrMode) -> Self {
+        match mode {
+            StderrMode::Log => "log".into(),
+            StderrMode::Inherit => "inherit".into(),
+            StderrMode::Null => "null".into(),
+            StderrMode::File(path) => format!("file:{}", path.display()),
+        }
+    }
+}
+
+/// How browsers talk to the native binary of an app manifest
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestMode {
+    /// Any number of messages in both directions, as with `connectNative` (default)
+    #[default]
+    Port,
+    /// A single message in each direction, as with `sendNativeMessage`
+    Oneshot,
+}
+
+/// Runtime behavior of the daemon for a particular app manifest
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)] // Strict mode
+pub struct ManifestSettings {
+    /// Paths opened and passed to the native binary as file descriptors 3, 4, ...
+    pub pass_fds: Vec<PathBuf>,
+    /// Seconds to keep the native binary running for reuse after the browser disconnects
+    pub persistent_ttl: Option<u64>,
+    /// Overrides the daemon-wide handling of the native binary's stderr
+    pub stderr: Option<StderrMode>,
+    /// Maximum size of messages from the native binary to the browser, larger ones end the session
+    pub max_output_size: Option<u32>,
+    /// Log the output of the native binary line by line while forwarding it without framing
+    pub debug_output: bool,
+    /// Bytes of address space the native binary may use
+    pub memory_limit: Option<u64>,
+    /// Seconds of CPU time after which the native binary is killed
+    pub cpu_time_limit: Option<u64>,
+    /// Maximum number of open file descriptors of the native binary
+    pub open_files_limit: Option<u64>,
+    /// End the session after the first response in `Oneshot` mode
+    pub mode: ManifestMode,
+    /// Directory into which the stdio of each session's native binary is captured
+    pub capture_dir: Option<PathBuf>,
+}
+
+/// Runtime behavior of the daemon, derived from the `[daemon]` configuration
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)] // Strict mode
+pub struct DaemonSettings {
+    /// Number of stderr lines per session logged as warnings before switching to debug
+    pub stderr_warn_lines: usize,
+    /// UID that connecting clients must have, defaults to the UID of the daemon
+    pub allowed_uid: Option<u32>,
+    /// Command run in the background whenever a native binary is launched
+    pub on_launch: Option<PathBuf>,
+    /// Fifo or Unix socket that connection lifecycle events are written to as JSON lines
+    pub event_stream: Option<PathBuf>,
+    /// Accept lz4 compression of frame bodies if offered by the client
+    pub compression: bool,
+    /// Seconds between keepalive pings to clients that support them
+    pub keepalive_interval: Option<u64>,
+    /// Handling of native binary stderr unless overridden for the app manifest
+    pub stderr: StderrMode,
+    /// Level at which accepted connections are logged before their handshake
+    pub accept_log_level: LogLevel,
+    /// Number of connections served concurrently per browser, unbounded if unset
+    pub workers: Option<NonZeroUsize>,
+    /// Queued connections that trigger an overload warning when sustained, defaults to `workers`
+    pub queue_warn_depth: Option<usize>,
+    /// Seconds that a graceful shutdown may take before the daemon exits regardless
+    pub shutdown_timeout: u64,
+    /// Milliseconds after launching within which exiting without output is reported as a crash
+    pub crash_window_ms: u64,
+    /// Times a failed listener is restarted before the daemon gives up and shuts down
+    pub listener_restarts: u32,
+    /// Seconds without sessions after which the daemon exits, to be activated again by systemd
+    pub idle_exit: Option<u64>,
+    /// Message sent to the browser when the daemon shuts down during a session
+    pub shutdown_message: Option<serde_json::Value>,
+    /// Backlog of the sockets bound by the daemon itself in `--bind` mode
+    pub backlog: Option<u32>,
+    /// Environment variables of the daemon that native binaries don't inherit
+    pub env_remove: Vec<String>,
+    /// Milliseconds above which forwarding a message is logged, latency isn't measured if unset
+    pub latency_threshold_ms: Option<u64>,
+    /// Default log level of the daemon when RUST_LOG is unset
+    pub log_level: Option<LogLevel>,
+    /// Pass the ID or origin of the connecting extension to native binaries in the environment
+    pub extension_id_env: bool,
+    /// Names of the sockets passed by systemd for browsers whose socket isn't named after them
+    pub socket_names: HashMap<String, String>,
+    /// Settings for app manifests by file name
+    pub manifests: HashMap<String, ManifestSettings>,
+}
+
+impl Default for DaemonSettings {
+    fn default() -> Self {
+        Self {
+            stderr_warn_lines: 20,
+            allowed_uid: None,
+            on_launch: None,
+            event_stream: None,
+            compression: false,
+            keepalive_interval: None,
+            stderr: StderrMode::default(),
+            accept_log_level: LogLevel::Info,
+            workers: None,
+            queue_warn_depth: None,
+            shutdown_timeout: 30,
+            crash_window_ms: 1000,
+            listener_restarts: 0,
+            idle_exit: None,
+            shutdown_message: None,
+            backlog: None,
+            env_remove: Vec::new(),
+            latency_threshold_ms: None,
+            log_level: None,
+            extension_id_env: false,
+            socket_names: HashMap::new(),
+            manifests: HashMap::new(),
+        }
+    }
+}
+
+impl DaemonSettings {
+    /// Name of the socket of `browser`, as passed by systemd
+    pub fn socket_name<'a>(&'a self, browser: &'a str) -> &'a str {
+        self.socket_names.get(browser).map_or(browser, |n| n)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(deny_unknown_fields)] // Strict mode
 pub struct Settings {
     pub native_binaries: NativeBinaryMap,
+    #[serde(default)]
+    pub daemon: DaemonSettings,
+}
+
+/// Settings file in `dir`, the file name or an absolute path can be overridden in the environment
+fn settings_path(dir: impl AsRef<Path>) -> Result<PathBuf> {
+    Ok(dir.as_ref().join(common::parse_env(
+        SETTINGS_FILE_ENV,
+        Some(SETTINGS_FILE_NAME),
+    )?))
 }
 
 impl Settings {
     #[instrument(level = "info", skip(dir), fields(dir = %dir.as_ref().display()))]
     pub async fn save(&self, dir: impl AsRef<Path>) -> Result<()> {
+        super::check_runtime_dir(&dir)?;
+        let path = settings_path(&dir)?;
         fs::write(
-            dir.as_ref().join(SETTINGS_FILE_NAME),
+            &path,
             &toml::to_string_pretty(self).context("Failed to serialize runtime settings")?,
         )
         .await
-        .map_err(|e| Error::from(e).context("Failed to write runtime settings"))
+        .map_err(|e| super::full_dir_context(e, &dir).context(path.display().to_string()))
+        .context("Failed to write runtime settings")
     }
 
     #[instrument(level = "info", skip(dir), fields(dir = %dir.as_ref().display()))]
     pub async fn load(dir: impl AsRef<Path>) -> Result<Self> {
-        toml::from_str(
-            &fs::read_to_string(dir.as_ref().join(SETTINGS_FILE_NAME))
-                .await
-                .map_err(|e| Error::from(e).context("Failed to read runtime settings"))?,
-        )
-        .context("Failed to deserialize runtime settings")
+        let path = settings_path(&dir)?;
+        let contents = fs::read(&path)
+            .await
+            .map_err(|e| Error::from(e).context(path.display().to_string()))
+            .context("Failed to read runtime settings")?;
+
+        // Setup only writes UTF-8, so the file was damaged after it was written
+        let contents = String::from_utf8(contents).map_err(|e| {
+            let damage = match toml::from_str::<Self>(&String::from_utf8_lossy(e.as_bytes())) {
+                Ok(_) => "the rest of the file is intact",
+                Err(_) => "the file is damaged beyond that",
+            };
+            anyhow!(
+                "{}: invalid UTF-8 at byte {}, {damage}. The runtime settings are corrupt, \
+                run setup again to regenerate them",
+                path.display(),
+                e.utf8_error().valid_up_to(),
+            )
+        })?;
+        toml::from_str(&contents).context("Failed to deserialize runtime settings")
     }
 }
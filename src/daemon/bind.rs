@@ -0,0 +1,101 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common;
+use anyhow::{bail, Context, Result};
+#[cfg(not(target_os = "linux"))]
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::socket::{self, AddressFamily, Backlog, SockFlag, SockType, UnixAddr};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Sockets bound by the daemon itself instead of systemd, which are removed when dropped
+#[derive(Default)]
+pub struct BoundSockets {
+    paths: Vec<PathBuf>,
+}
+
+impl BoundSockets {
+    /// Binds the socket of each browser in `runtime_dir`, returning them by their socket
+    /// name like systemd would. The backlog defaults to the kernel maximum, as with systemd.
+    pub fn bind<'a>(
+        runtime_dir: impl AsRef<Path>,
+        browsers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        backlog: Option<u32>,
+    ) -> Result<(Self, HashMap<String, OwnedFd>)> {
+        let backlog = match backlog {
+            Some(b) => Backlog::new(b as i32).context("Invalid socket backlog")?,
+            None => Backlog::MAXCONN,
+        };
+
+        let mut bound = Self::default();
+        let mut sockets = HashMap::new();
+        for (browser, socket) in browsers {
+            let path = runtime_dir.as_ref().join(common::socket_file_name(browser));
+            let fd = bind_socket(&path, backlog)
+                .with_context(|| path.display().to_string())
+                .context("Failed to bind socket")?;
+
+            bound.paths.push(path);
+            sockets.insert(socket.to_owned(), fd);
+        }
+
+        Ok((bound, sockets))
+    }
+}
+
+impl Drop for BoundSockets {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            match std::fs::remove_file(path) {
+                Ok(()) => debug!("removed socket {}", path.display()),
+                Err(e) => warn!("Failed to remove socket {}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+fn bind_socket(path: &Path, backlog: Backlog) -> Result<OwnedFd> {
+    // A socket left behind by a previous run is replaced, unless something still listens on it
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => bail!("Socket is already in use, is another daemon running?"),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                debug!("replacing stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(e.into()),
+        },
+        Ok(_) => bail!("Path exists and is not a socket"),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    // Native binaries must not inherit the listening socket
+    #[cfg(target_os = "linux")]
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    #[cfg(not(target_os = "linux"))]
+    let fd = {
+        let fd = socket::socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        )?;
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        fd
+    };
+    socket::bind(fd.as_raw_fd(), &UnixAddr::new(path)?)?;
+    socket::listen(&fd, backlog)?;
+    Ok(fd)
+}
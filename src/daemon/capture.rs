@@ -0,0 +1,229 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian, NativeEndian};
+use serde_json::Value;
+use std::io::Result as IoResult;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{Duration, Instant};
+use tracing::{warn, Instrument};
+
+/// Start of capture files, identifying the format version
+const MAGIC: &[u8] = b"NMPCAP1\n";
+/// Chunks start with their direction, microseconds since the session started and length
+const CHUNK_HEADER_SIZE: usize = 1 + 8 + 4;
+
+/// Direction of bytes exchanged with a native binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Direction {
+    ToHost = 0,
+    FromHost = 1,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ToHost => "to host",
+            Self::FromHost => "from host",
+        }
+    }
+}
+
+/// Records the stdio of a native binary into a capture file, as chunks of bytes tagged with
+/// their direction and the time since the session started. Chunks are written in the
+/// background, so that capturing never blocks the session.
+#[derive(Clone)]
+pub(crate) struct Capture {
+    started: Instant,
+    chunks: UnboundedSender<(Direction, Duration, Vec<u8>)>,
+}
+
+impl Capture {
+    /// Creates a capture file for session `id` of `browser` in `dir`, failures to write
+    /// into it later on are logged
+    pub async fn create(dir: &Path, browser: &str, id: u32) -> IoResult<Self> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{browser}-{millis}-{id}.capture"));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600) // Messages may well be private
+            .open(&path)
+            .await?;
+        let mut file = BufWriter::new(file);
+        file.write_all(MAGIC).await?;
+        file.flush().await?;
+
+        let (chunks, mut rx) = mpsc::unbounded_channel::<(Direction, Duration, Vec<u8>)>();
+        let write = async move {
+            while let Some((direction, elapsed, bytes)) = rx.recv().await {
+                let mut header = [0; CHUNK_HEADER_SIZE];
+                header[0] = direction as u8;
+                LittleEndian::write_u64(&mut header[1..9], elapsed.as_micros() as u64);
+                LittleEndian::write_u32(&mut header[9..], bytes.len() as u32);
+                file.write_all(&header).await?;
+                file.write_all(&bytes).await?;
+                file.flush().await?; // Readable while the session is still running
+            }
+            IoResult::Ok(())
+        };
+        tokio::spawn(
+            async move {
+                if let Err(e) = write.await {
+                    warn!("writing capture {} failed: {e}", path.display());
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Self {
+            started: Instant::now(),
+            chunks,
+        })
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            let _ = self
+                .chunks
+                .send((direction, self.started.elapsed(), bytes.to_vec()));
+        }
+    }
+}
+
+/// Output of a native binary, captured as it is read if a capture is given
+pub(crate) struct CaptureReader<R> {
+    inner: R,
+    capture: Option<Capture>,
+}
+
+impl<R> CaptureReader<R> {
+    pub fn new(inner: R, capture: Option<Capture>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CaptureReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(capture)) = (&result, &this.capture) {
+            capture.record(Direction::FromHost, &buf.filled()[filled..]);
+        }
+        result
+    }
+}
+
+/// Input of a native binary, captured as it is written if a capture is given
+pub(crate) struct CaptureWriter<W> {
+    inner: W,
+    capture: Option<Capture>,
+}
+
+impl<W> CaptureWriter<W> {
+    pub fn new(inner: W, capture: Option<Capture>) -> Self {
+        Self { inner, capture }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CaptureWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(capture)) = (&result, &this.capture) {
+            capture.record(Direction::ToHost, &buf[..*n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Native messaging message decoded from a capture, at the time since the session started
+/// at which its last byte was exchanged
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedMessage {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub message: Value,
+}
+
+/// Decodes the messages of a capture file in the order they were exchanged. Messages that
+/// aren't valid JSON are decoded as strings, and an incomplete last message in either
+/// direction, e.g. of a session that broke off, is left out.
+pub async fn read_capture(path: impl AsRef<Path>) -> Result<Vec<CapturedMessage>> {
+    let path = path.as_ref();
+    let contents = fs::read(path)
+        .await
+        .with_context(|| path.display().to_string())
+        .context("Unable to read capture")?;
+    let Some(mut rest) = contents.strip_prefix(MAGIC) else {
+        bail!("{} is not a session capture", path.display());
+    };
+
+    let mut messages = Vec::new();
+    let mut pending = [Vec::new(), Vec::new()];
+    while !rest.is_empty() {
+        if rest.len() < CHUNK_HEADER_SIZE {
+            bail!("{} is truncated", path.display());
+        }
+        let direction = match rest[0] {
+            0 => Direction::ToHost,
+            1 => Direction::FromHost,
+            d => bail!("{} has a chunk of unknown direction {d}", path.display()),
+        };
+        let elapsed = Duration::from_micros(LittleEndian::read_u64(&rest[1..9]));
+        let length = LittleEndian::read_u32(&rest[9..CHUNK_HEADER_SIZE]) as usize;
+        let Some(chunk) = rest.get(CHUNK_HEADER_SIZE..CHUNK_HEADER_SIZE + length) else {
+            bail!("{} is truncated", path.display());
+        };
+        rest = &rest[CHUNK_HEADER_SIZE + length..];
+
+        // Chunks are split wherever reads and writes were, not at message boundaries
+        let buf = &mut pending[direction as usize];
+        buf.extend_from_slice(chunk);
+        while buf.len() >= 4 {
+            let end = 4 + NativeEndian::read_u32(buf) as usize;
+            if buf.len() < end {
+                break;
+            }
+            let body = &buf[4..end];
+            let message = serde_json::from_slice(body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into()));
+            messages.push(CapturedMessage {
+                elapsed,
+                direction,
+                message,
+            });
+            buf.drain(..end);
+        }
+    }
+
+    Ok(messages)
+}
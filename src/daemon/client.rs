@@ -1,35 +1,90 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use anyhow::{anyhow, Context, Result};
+use crate::common::constants::*;
+use crate::common::keepalive::Link;
+use crate::common::runtime::{DaemonSettings, ManifestMode, ManifestSettings, StderrMode};
+use crate::common::{
+    forward_frame_body, recv_nm_object, send_nm_object, FrameCodec, HandshakeMessage,
+    HandshakeReply,
+};
+use crate::daemon::capture::{Capture, CaptureReader, CaptureWriter};
+use crate::daemon::events::EventStream;
+use crate::daemon::persistent::{forward_host_output, HostKey, HostPool, PersistentHost};
+use crate::daemon::{fds, limits};
+use anyhow::{anyhow, Context, Error, Result};
 use libc::pid_t;
 use nix::sys::signal;
 use nix::sys::signal::Signal;
 use nix::unistd::Pid;
+use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::future;
+use std::io::ErrorKind;
+use std::path::Path;
 use std::process::Stdio;
-use std::sync::Arc;
-use tokio::io::{copy, AsyncBufReadExt, AsyncRead, BufReader};
+use std::sync::{Arc, OnceLock};
+use tokio::io::{self, copy, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
 use tokio::select;
 use tokio::task::JoinSet;
 use tokio::time;
+use tokio::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, info, instrument, warn};
+use tracing::{debug, info, instrument, warn, Instrument};
 
-use nm_proxy::common::{recv_nm_object, HandshakeMessage};
+/// Time allowed for delivering the shutdown message to the browser
+const SHUTDOWN_MESSAGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Minimum interval between summaries of stderr lines demoted to debug level
+const STDERR_SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
 
 pub struct ClientTaskConfig {
     pub browser: String,
     pub stream: UnixStream,
+    pub peer_pid: i32,
+    pub peer_uid: u32,
     pub bin_map: Arc<HashMap<String, String>>,
+    pub settings: Arc<DaemonSettings>,
+    pub(crate) hosts: Arc<HostPool>,
+    pub(crate) events: EventStream,
     pub token: CancellationToken,
 }
 
 impl ClientTaskConfig {
-    #[instrument(skip_all, fields(id = _id, browser = self.browser, manifest), err)]
+    /// Serves the session, emitting its teardown event however it ends
+    pub(crate) async fn serve(self, id: u32) -> Result<()> {
+        let (events, browser) = (self.events.clone(), self.browser.clone());
+        let started = Instant::now();
+        let result = self.launch(id).await;
+
+        let details = json!({
+            "duration_ms": started.elapsed().as_millis() as u64,
+            "error": result.as_ref().err().map(|e| format!("{e:#}")),
+        });
+        events.emit("teardown", &browser, id, details);
+        result
+    }
+
+    #[instrument(
+        skip_all,
+        fields(
+            id = _id,
+            browser = self.browser,
+            peer_pid = self.peer_pid,
+            peer_uid = self.peer_uid,
+            manifest,
+            profile,
+            bytes_to_host,
+            bytes_from_host,
+            host_exit
+        ),
+        err
+    )]
     pub(crate) async fn launch(self, _id: u32) -> Result<()> {
         info!("waiting for handshake");
         let (mut stream_rx, mut stream_tx) = self.stream.into_split();
@@ -39,77 +94,267 @@ impl ClientTaskConfig {
 
         // Register the manifest name into the instrumentation
         tracing::Span::current().record("manifest", &handshake.manifest_name);
+        if let Some(profile) = &handshake.profile {
+            tracing::Span::current().record("profile", profile);
+        }
         info!("client connected");
+        let details = json!({
+            "manifest": handshake.manifest_name,
+            "profile": handshake.profile,
+            "protocol_version": handshake.protocol_version,
+        });
+        self.events.emit("handshake", &self.browser, _id, details);
+
+        // Legacy clients don't negotiate, their traffic is forwarded without framing checks
+        let (framing_to_host, framing_from_host, keepalive_interval) =
+            match handshake.protocol_version {
+                0 => (None, None, None),
+                _ => {
+                    let reply = negotiate(&mut stream_tx, &handshake, &self.settings).await?;
+                    let max_size = reply.max_message_size;
+                    let (to_host, from_host) = match reply.compression {
+                        true => {
+                            debug!("compressing frame bodies");
+                            (FrameCodec::Decompress, FrameCodec::Compress)
+                        }
+                        false => (FrameCodec::Plain, FrameCodec::Plain),
+                    };
+
+                    let keepalive_interval = reply.keepalive_interval.map(Duration::from_secs);
+                    (
+                        Some((max_size, to_host)),
+                        Some((max_size, from_host)),
+                        keepalive_interval,
+                    )
+                }
+            };
 
-        let binary = self.bin_map.get(&handshake.manifest_name).ok_or(anyhow!(
-            "Native binary for {} not registered",
-            handshake.manifest_name
-        ))?;
+        // Shared between forwarded data and control frames
+        let link = match self.settings.latency_threshold_ms {
+            Some(ms) => Link::new(stream_tx).with_latency(
+                ["to the browser", "to the native binary"],
+                Duration::from_millis(ms),
+            ),
+            None => Link::new(stream_tx),
+        };
+        let link = Arc::new(link);
+        let _latency = LatencySummary(link.clone());
+
+        let binary = self
+            .bin_map
+            .get(&handshake.manifest_name)
+            .ok_or(anyhow!(
+                "Native binary for {} not registered",
+                handshake.manifest_name
+            ))?
+            .clone();
 
-        info!("launching native binary: {}", binary);
         debug!("handshake args: {:?}", handshake.args);
 
-        // Start the native binary as a subprocess
-        let mut child = Command::new(binary)
-            .args(handshake.args) // Pass through the arguments from the browser
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()?;
+        let manifest_settings = self
+            .settings
+            .manifests
+            .get(&handshake.manifest_name)
+            .cloned()
+            .unwrap_or_default();
+
+        // Capping output and one-shot sessions require framing, which is also applied towards
+        // legacy clients
+        let oneshot = manifest_settings.mode == ManifestMode::Oneshot;
+        let framing_from_host = match manifest_settings.max_output_size {
+            Some(cap) => framing_from_host
+                .or(Some((cap, FrameCodec::Plain)))
+                .map(|(max_size, codec)| (max_size.min(cap), codec)),
+            None if oneshot => framing_from_host.or(Some((MAX_MESSAGE_SIZE, FrameCodec::Plain))),
+            None => framing_from_host,
+        };
+
+        // Persistent native binaries can only be detached between frames
+        if let Some(ttl) = manifest_settings.persistent_ttl {
+            match (framing_to_host, framing_from_host) {
+                (Some(to_host), Some(from_host)) => {
+                    return PersistentSession {
+                        browser: self.browser,
+                        settings: self.settings,
+                        hosts: self.hosts,
+                        events: self.events,
+                        token: self.token,
+                        key: (handshake.manifest_name.clone(), handshake.args.clone()),
+                        ttl: Duration::from_secs(ttl),
+                        to_host,
+                        from_host,
+                        keepalive_interval,
+                    }
+                    .run(
+                        _id,
+                        &binary,
+                        &handshake,
+                        &manifest_settings,
+                        stream_rx,
+                        link,
+                    )
+                    .await;
+                }
+                _ => debug!("legacy client, not keeping the native binary running"),
+            }
+        }
+
+        // Output of native binaries under development is logged and forwarded as-is
+        let debug_output = manifest_settings.debug_output;
+        if debug_output && framing_from_host.is_some_and(|(_, c)| c != FrameCodec::Plain) {
+            warn!(
+                "debug_output forwards raw output, which the compressing proxy client can't decode"
+            );
+        }
+        let framing_from_host = framing_from_host.filter(|_| !debug_output);
+        if debug_output && oneshot {
+            warn!("debug_output can't tell where the response ends, ignoring mode = \"oneshot\"");
+        }
 
-        let mut child_stdin = child.stdin.take().unwrap();
-        let mut child_stdout = child.stdout.take().unwrap();
+        let mut child = spawn_binary(
+            &binary,
+            &handshake,
+            &manifest_settings,
+            &self.settings,
+            &self.browser,
+        )?;
+        let launched = Instant::now();
+        let details =
+            json!({"manifest": handshake.manifest_name, "binary": binary, "pid": child.id()});
+        self.events.emit("launched", &self.browser, _id, details);
+        let crash_window = Duration::from_millis(self.settings.crash_window_ms);
 
-        let child_stderr = child.stderr.take().unwrap();
+        // Captured at the stdio of the native binary, where messages are neither framed for
+        // the link nor compressed
+        let capture = match &manifest_settings.capture_dir {
+            Some(dir) => match Capture::create(dir, &self.browser, _id).await {
+                Ok(capture) => Some(capture),
+                Err(e) => {
+                    warn!("unable to capture the session into {}: {e}", dir.display());
+                    None
+                }
+            },
+            None => None,
+        };
+        let mut child_stdin = CaptureWriter::new(child.stdin.take().unwrap(), capture.clone());
+        let mut child_stdout = CaptureReader::new(child.stdout.take().unwrap(), capture);
+
+        // Only piped in the "log" stderr mode
+        let child_stderr = child.stderr.take();
         let binary_clone = binary.clone();
+        let warn_lines = self.settings.stderr_warn_lines;
+
+        // Byte counts are recorded into the session span once each direction finishes
+        let span = tracing::Span::current();
+        let span_clone = span.clone();
+
+        // Set once the native binary closes its output, usually by exiting
+        let output = Arc::new(OnceLock::new());
+        let output_clone = output.clone();
 
         // This will abort all nested tasks when dropped
         let mut set = JoinSet::new();
-        set.spawn(async move { copy(&mut child_stdout, &mut stream_tx).await.map(|_| ()) });
-        set.spawn(async move { copy(&mut stream_rx, &mut child_stdin).await.map(|_| ()) });
-        set.spawn(
-            async move { stderr_task(child_stderr, _id, &self.browser, &*binary_clone).await },
-        );
+        let link_clone = link.clone();
+        let reconnect = handshake.reconnect && handshake.protocol_version > 0;
+        let mut host_tasks = Vec::new(); // Tasks that end when the native binary exits
+        let from_host = set.spawn(async move {
+            let n = forward_from_host(
+                &mut child_stdout,
+                &link_clone,
+                framing_from_host,
+                (debug_output, oneshot),
+            )
+            .await
+            .map_err(oversized_output_context)?;
+            span.record("bytes_from_host", n);
+            let _ = output_clone.set((n, launched.elapsed()));
+
+            // The native binary closed its output, so the client shouldn't reconnect
+            if reconnect {
+                link_clone.send_session_end().await?;
+            }
+            Ok(())
+        });
+        host_tasks.push(from_host.id());
+        let link_clone = link.clone();
+        set.spawn(async move {
+            match forward_to_host(
+                &mut stream_rx,
+                &mut child_stdin,
+                &link_clone,
+                framing_to_host,
+            )
+            .await
+            {
+                Ok(n) => {
+                    span_clone.record("bytes_to_host", n);
+                    Ok(())
+                }
+                // The host no longer wants input, keep forwarding its output until it exits
+                Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                    discard_input(&mut stream_rx).instrument(span_clone).await
+                }
+                Err(e) => Err(e),
+            }
+        });
+        if let Some(child_stderr) = child_stderr {
+            let browser = self.browser.clone();
+            let stderr = set.spawn(async move {
+                stderr_task(child_stderr, _id, &browser, &binary_clone, warn_lines).await
+            });
+            host_tasks.push(stderr.id());
+        }
+
+        if let Some(interval) = keepalive_interval {
+            let link = link.clone();
+            set.spawn(async move { link.keepalive(interval).await });
+        }
 
-        // Dummy task for triggering cancellation
+        // Dummy task for triggering cancellation, which notifies the browser if configured
+        let link_clone = link.clone();
         set.spawn(async move {
             self.token.cancelled().await;
+            if let (Some(message), Some(framing)) =
+                (&self.settings.shutdown_message, framing_from_host)
+            {
+                send_shutdown_message(&link_clone, message, framing).await;
+            }
             Ok(())
         });
 
         let mut aborted = false;
-        while let Some(a) = set.join_next().await {
-            match a {
-                Ok(Ok(_)) => (),
-                Ok(Err(e)) => Err(e).context("IO task error")?,
-                Err(e) if e.is_cancelled() => (), // Cancellations are expected
+        while let Some(a) = set.join_next_with_id().await {
+            let id = match a {
+                Ok((id, Ok(_))) => id,
+                Ok((_, Err(e))) => Err(e).context("IO task error")?,
+                Err(e) if e.is_cancelled() => e.id(), // Cancellations are expected
                 Err(e) => Err(e).context("IO task join failed")?,
-            }
+            };
 
             if !aborted {
                 aborted = true;
 
-                // Give the application a little time to react to stdio being closed
-                time::sleep(time::Duration::from_millis(200)).await;
-
-                // Send SIGTERM to native binary (regardless of task that quit)
-                if let Some(id) = child.id() {
-                    signal::kill(Pid::from_raw(id as pid_t), Signal::SIGTERM).unwrap();
+                // Give the application a little time to react to stdio being closed, unless
+                // the proxy client was terminated, which shouldn't leave it running any longer
+                match link.terminated() {
+                    true => info!("proxy client was terminated, stopping the native binary"),
+                    false => time::sleep(time::Duration::from_millis(200)).await,
                 }
 
-                // Wait for 10 seconds for the process to quit
-                select! {
-                    _ = time::sleep(time::Duration::from_secs(10)) => {
-                        warn!("timeout reached, killing {binary}");
-                        child.kill().await.with_context(|| format!("failed to kill {binary}"))?;
-                    }
-                    res = child.wait() => {
-                        match res {
-                            Ok(s) => info!("{binary}: {s}"),
-                            Err(e) => Err(e)
-                                .with_context(|| format!("{binary}: unclean shutdown"))?
-                        };
-                    }
+                // Only sessions ended by the native binary tell how it exited
+                let exit = match host_tasks.contains(&id) {
+                    true => HostExit::classify(output.get().copied(), crash_window),
+                    false => HostExit::Stopped,
+                };
+                tracing::Span::current().record("host_exit", exit.as_str());
+                terminate_child(&mut child, &binary).await?;
+                let details =
+                    json!({"manifest": handshake.manifest_name, "host_exit": exit.as_str()});
+                self.events.emit("exited", &self.browser, _id, details);
+                if exit == HostExit::Crashed {
+                    warn!(
+                        "{binary} exited without responding within {crash_window:?}, it may have crashed"
+                    );
                 }
 
                 // Abort all IO tasks after first task has finished
@@ -121,27 +366,524 @@ impl ClientTaskConfig {
     }
 }
 
-/// Prints warning messages from stderr of a child process
+/// How the native binary of a session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HostExit {
+    /// Kept running until the session ended otherwise, e.g. by the browser disconnecting
+    Stopped,
+    /// Exited after writing output, like one-shot native binaries do
+    Responded,
+    /// Exited without writing any output, after running for longer than the crash window
+    Exited,
+    /// Exited without writing any output within the crash window
+    Crashed,
+}
+
+impl HostExit {
+    /// Classifies the exit from the bytes the native binary wrote and how long after launching
+    /// it closed its output, if it did before the session ended
+    fn classify(output: Option<(u64, Duration)>, crash_window: Duration) -> Self {
+        match output {
+            None => Self::Stopped,
+            Some((n, _)) if n > 0 => Self::Responded,
+            Some((_, elapsed)) if elapsed > crash_window => Self::Exited,
+            Some(_) => Self::Crashed,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Stopped => "stopped",
+            Self::Responded => "responded",
+            Self::Exited => "exited",
+            Self::Crashed => "crashed",
+        }
+    }
+}
+
+/// Logs the latency summaries of a session's link once the session ends
+struct LatencySummary(Arc<Link<OwnedWriteHalf>>);
+
+impl Drop for LatencySummary {
+    fn drop(&mut self) {
+        if let Some((into_link, out_of_link)) = self.0.latency() {
+            for summary in [into_link.summary(), out_of_link.summary()]
+                .into_iter()
+                .flatten()
+            {
+                info!("{summary}");
+            }
+        }
+    }
+}
+
+/// Starts the native binary with piped stdin and stdout, stderr according to the configured
+/// mode, additional file descriptors and the launch hook
+pub(crate) fn spawn_binary(
+    binary: &str,
+    handshake: &HandshakeMessage,
+    manifest_settings: &ManifestSettings,
+    settings: &DaemonSettings,
+    browser: &str,
+) -> Result<Child> {
+    // Start the native binary as a subprocess
+    info!("launching native binary: {}", binary);
+    let mut command = Command::new(binary);
+    command
+        .args(&handshake.args) // Pass through the arguments from the browser
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(stderr_stdio(
+            manifest_settings
+                .stderr
+                .as_ref()
+                .unwrap_or(&settings.stderr),
+        )?)
+        .kill_on_drop(true);
+
+    limits::apply_limits(&mut command, manifest_settings);
+
+    // Keep e.g. agent sockets of the session away from native binaries
+    for name in &settings.env_remove {
+        command.env_remove(name);
+    }
+
+    // Lets native binaries behave differently per browser profile
+    match &handshake.profile {
+        Some(profile) => command.env(PROFILE_ENV, profile),
+        None => command.env_remove(PROFILE_ENV),
+    };
+
+    if settings.extension_id_env {
+        match extension_id(&handshake.args) {
+            Some(id) => command.env(EXTENSION_ID_ENV, id),
+            None => command.env_remove(EXTENSION_ID_ENV),
+        };
+    }
+
+    // Pass additional file descriptors if configured, these are closed after spawning
+    let pass_fds = &manifest_settings.pass_fds;
+    let extra_fds = if pass_fds.is_empty() {
+        Vec::new()
+    } else {
+        debug!("passing file descriptors: {:?}", pass_fds);
+        let extra_fds = fds::open_pass_fds(pass_fds)?;
+        fds::pass_fds(&mut command, &extra_fds);
+        extra_fds
+    };
+
+    let child = command.spawn()?;
+    drop(extra_fds);
+
+    if let Some(hook) = &settings.on_launch {
+        spawn_launch_hook(hook, browser, &handshake.manifest_name, child.id());
+    }
+
+    Ok(child)
+}
+
+/// ID of the extension that connected, passed by Firefox after the app manifest path, or
+/// the origin of the extension passed by Chromium-based browsers
+fn extension_id(args: &[String]) -> Option<&str> {
+    args.iter()
+        .find(|a| a.starts_with("chrome-extension://"))
+        .or_else(|| args.get(1))
+        .map(String::as_str)
+}
+
+/// Stderr configuration of the native binary for `mode`
+fn stderr_stdio(mode: &StderrMode) -> Result<Stdio> {
+    Ok(match mode {
+        StderrMode::Log => Stdio::piped(),
+        StderrMode::Inherit => Stdio::inherit(),
+        StderrMode::Null => Stdio::null(),
+        StderrMode::File(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| path.display().to_string())
+            .context("Failed to open native binary stderr file")?
+            .into(),
+    })
+}
+
+/// Session with a native binary that is kept running after the browser disconnects
+struct PersistentSession {
+    browser: String,
+    settings: Arc<DaemonSettings>,
+    hosts: Arc<HostPool>,
+    events: EventStream,
+    token: CancellationToken,
+    key: HostKey,
+    ttl: Duration,
+    to_host: (u32, FrameCodec),
+    from_host: (u32, FrameCodec),
+    keepalive_interval: Option<Duration>,
+}
+
+impl PersistentSession {
+    async fn run(
+        self,
+        id: u32,
+        binary: &str,
+        handshake: &HandshakeMessage,
+        manifest_settings: &ManifestSettings,
+        mut stream_rx: OwnedReadHalf,
+        link: Arc<Link<OwnedWriteHalf>>,
+    ) -> Result<()> {
+        let mut host = match self.hosts.take(&self.key) {
+            Some(host) => {
+                info!("reattaching to running native binary: {binary}");
+                host
+            }
+            None => {
+                let mut child = spawn_binary(
+                    binary,
+                    handshake,
+                    manifest_settings,
+                    &self.settings,
+                    &self.browser,
+                )?;
+                let details = json!({
+                    "manifest": handshake.manifest_name,
+                    "binary": binary,
+                    "pid": child.id(),
+                    "persistent": true,
+                });
+                self.events.emit("launched", &self.browser, id, details);
+
+                // Outlives this session, ends when the native binary exits
+                if let Some(stderr) = child.stderr.take() {
+                    let (browser, binary_clone) = (self.browser.clone(), binary.to_owned());
+                    let warn_lines = self.settings.stderr_warn_lines;
+                    tokio::spawn(async move {
+                        stderr_task(stderr, id, &browser, &binary_clone, warn_lines).await
+                    });
+                }
+
+                PersistentHost {
+                    binary: binary.to_owned(),
+                    stdin: child.stdin.take().unwrap(),
+                    stdout: BufReader::new(child.stdout.take().unwrap()),
+                    child,
+                }
+            }
+        };
+
+        let (to_host, from_host) = {
+            // Detaching stops forwarding host output at the next frame boundary
+            let detach = CancellationToken::new();
+            let PersistentHost { stdin, stdout, .. } = &mut host;
+            let to_host = async {
+                let res = link.forward_to(&mut stream_rx, stdin, self.to_host).await;
+                if matches!(&res, Err(e) if e.kind() == ErrorKind::BrokenPipe) {
+                    // Exiting, the output of the native binary is forwarded until it closes
+                    return discard_input(&mut stream_rx).await;
+                }
+                detach.cancel();
+                res
+            };
+            let from_host = async {
+                forward_host_output(stdout, &link, self.from_host, &detach)
+                    .await
+                    .map_err(oversized_output_context)
+            };
+            let keepalive = async {
+                match self.keepalive_interval {
+                    Some(interval) => link.keepalive(interval).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::pin!(to_host, from_host, keepalive);
+
+            select! {
+                res = &mut to_host => (Some(res), from_host.await),
+                res = &mut from_host => (None, res), // Native binary exited
+                res = &mut keepalive => {
+                    detach.cancel();
+                    (Some(res.map(|_| 0)), from_host.await)
+                }
+                _ = self.token.cancelled() => {
+                    detach.cancel();
+                    let res = from_host.await;
+                    if let Some(message) = &self.settings.shutdown_message {
+                        send_shutdown_message(&link, message, self.from_host).await;
+                    }
+                    (None, res)
+                }
+            }
+        };
+
+        let span = tracing::Span::current();
+        if let Some(Ok(n)) = &to_host {
+            span.record("bytes_to_host", n);
+        }
+        if let Ok((n, _)) = &from_host {
+            span.record("bytes_from_host", n);
+        }
+
+        match (to_host, from_host) {
+            // The browser disconnected between frames, keep the native binary for reattaching
+            (Some(Ok(_)), Ok((_, false))) if !self.token.is_cancelled() => {
+                debug!("keeping native binary running for {:?}", self.ttl);
+                self.hosts.park(self.key, host, self.ttl, self.token);
+                Ok(())
+            }
+            (to_host, from_host) => {
+                // The native binary closed its output, so the client shouldn't reconnect
+                if handshake.reconnect && matches!(from_host, Ok((_, true))) {
+                    if let Err(e) = link.send_session_end().await {
+                        debug!("unable to mark the end of the session: {e}");
+                    }
+                }
+
+                host.terminate().await;
+                let details = json!({"manifest": handshake.manifest_name, "persistent": true});
+                self.events.emit("exited", &self.browser, id, details);
+                to_host.transpose().context("IO task error")?;
+                from_host.context("IO task error")?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Terminates a native binary with SIGTERM, killing it if that doesn't suffice
+pub(crate) async fn terminate_child(child: &mut Child, binary: &str) -> Result<()> {
+    // Send SIGTERM to native binary (regardless of task that quit)
+    if let Some(id) = child.id() {
+        signal::kill(Pid::from_raw(id as pid_t), Signal::SIGTERM).unwrap();
+    }
+
+    // Wait for 10 seconds for the process to quit
+    select! {
+        _ = time::sleep(time::Duration::from_secs(10)) => {
+            warn!("timeout reached, killing {binary}");
+            child.kill().await.with_context(|| format!("failed to kill {binary}"))?;
+        }
+        res = child.wait() => {
+            match res {
+                Ok(s) => info!("{binary}: {s}"),
+                Err(e) => Err(e)
+                    .with_context(|| format!("{binary}: unclean shutdown"))?
+            };
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the launch hook in the background, failures are logged without affecting the session
+fn spawn_launch_hook(hook: &Path, browser: &str, manifest: &str, pid: Option<u32>) {
+    let mut command = Command::new(hook);
+    command
+        .env("NM_PROXY_BROWSER", browser)
+        .env("NM_PROXY_MANIFEST", manifest)
+        .stdin(Stdio::null());
+    if let Some(pid) = pid {
+        command.env("NM_PROXY_PID", pid.to_string());
+    }
+
+    let hook = hook.display().to_string();
+    tokio::spawn(
+        async move {
+            match command.status().await {
+                Ok(s) if s.success() => debug!("launch hook {hook}: {s}"),
+                Ok(s) => warn!("launch hook {hook}: {s}"),
+                Err(e) => warn!("launch hook {hook} failed: {e}"),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Replies to a client handshake, returning the negotiated reply
+async fn negotiate(
+    writer: &mut (impl AsyncWrite + Unpin),
+    handshake: &HandshakeMessage,
+    settings: &DaemonSettings,
+) -> Result<HandshakeReply> {
+    // Version skew is often a proxy client that wasn't redeployed after upgrading
+    let daemon_version = env!("CARGO_PKG_VERSION");
+    match &handshake.client_version {
+        Some(v) if v == daemon_version => (),
+        Some(v) => warn!(
+            "proxy client version {v} differs from daemon version {daemon_version}, \
+            re-run setup and restart the daemon after upgrading"
+        ),
+        None => debug!("proxy client predates version reporting, its version is unknown"),
+    }
+
+    let client_version = handshake.protocol_version;
+    let mut reply = HandshakeReply {
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: handshake.max_message_size,
+        error: None,
+        compression: settings.compression && handshake.compression,
+        keepalive_interval: settings.keepalive_interval.filter(|_| handshake.keepalive),
+        reconnect: handshake.reconnect,
+        terminate: handshake.terminate,
+    };
+    reply.max_message_size = reply.max_frame_size();
+
+    if client_version > PROTOCOL_VERSION {
+        reply.error = Some(format!(
+            "Unsupported protocol version {client_version}, the daemon supports up to \
+            {PROTOCOL_VERSION}. Please update the nm-proxy daemon"
+        ));
+    }
+
+    send_nm_object(writer, &reply)
+        .await
+        .context("Sending handshake reply failed")?;
+
+    match reply.error {
+        Some(e) => Err(anyhow!(e)),
+        None => Ok(reply),
+    }
+}
+
+/// Sends `message` to the browser between frames, giving up after `SHUTDOWN_MESSAGE_TIMEOUT`
+async fn send_shutdown_message(
+    link: &Link<impl AsyncWrite + Unpin>,
+    message: &Value,
+    (max_size, codec): (u32, FrameCodec),
+) {
+    let send = async {
+        let body = serde_json::to_vec(message)?;
+        let length = u32::try_from(body.len())?;
+        let mut writer = link.writer().await;
+        forward_frame_body(length, &mut body.as_slice(), &mut *writer, max_size, codec).await?;
+        Ok::<_, Error>(())
+    };
+
+    match time::timeout(SHUTDOWN_MESSAGE_TIMEOUT, send).await {
+        Ok(Ok(())) => debug!("sent shutdown message"),
+        Ok(Err(e)) => warn!("unable to send shutdown message: {e:#}"),
+        Err(_) => warn!("timed out sending shutdown message"),
+    }
+}
+
+/// Attributes frame size violations in the output of the native binary to it
+fn oversized_output_context(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        ErrorKind::InvalidData => std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Native binary sent an invalid message, not forwarding it to the browser: {e}"),
+        ),
+        _ => e,
+    }
+}
+
+/// Forwards native binary output to the client, as size-checked frames if framing is given.
+/// Unframed output is logged line by line if `log_lines` is set, framed output ends after the
+/// first frame if `oneshot` is set.
+async fn forward_from_host(
+    reader: &mut (impl AsyncRead + Unpin),
+    link: &Link<impl AsyncWrite + Unpin>,
+    framing: Option<(u32, FrameCodec)>,
+    (log_lines, oneshot): (bool, bool),
+) -> std::io::Result<u64> {
+    match framing {
+        Some(framing) if oneshot => {
+            Ok(link.forward_frame_from(reader, framing).await?.unwrap_or(0))
+        }
+        Some(framing) => link.forward_from(reader, framing).await,
+        None if log_lines => copy_logging_lines(reader, &mut *link.writer().await).await,
+        None => copy(reader, &mut *link.writer().await).await,
+    }
+}
+
+/// Copies `reader` to `writer` a line at a time, logging each line without altering it
+async fn copy_logging_lines(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut total = 0;
+
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        info!(
+            "native binary output: {}",
+            String::from_utf8_lossy(&line).trim_end()
+        );
+        writer.write_all(&line).await?;
+        writer.flush().await?;
+        total += line.len() as u64;
+        line.clear();
+    }
+
+    Ok(total)
+}
+
+/// Forwards client input to the native binary, as size-checked frames if framing is given
+async fn forward_to_host(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    link: &Link<impl AsyncWrite + Unpin>,
+    framing: Option<(u32, FrameCodec)>,
+) -> std::io::Result<u64> {
+    match framing {
+        Some(framing) => link.forward_to(reader, writer, framing).await,
+        None => copy(reader, writer).await,
+    }
+}
+
+/// Discards further client input after the native binary closed its stdin, usually by
+/// exiting. Closing the connection with unread input would reset it, failing the proxy
+/// client instead of letting it see the session end.
+async fn discard_input<T>(reader: &mut (impl AsyncRead + Unpin)) -> T {
+    debug!("native binary closed stdin, discarding further input");
+    if let Err(e) = copy(reader, &mut io::sink()).await {
+        debug!("discarding input failed: {e}");
+    }
+    future::pending().await
+}
+
+/// Prints warning messages from stderr of a child process. After `warn_lines` lines,
+/// further output is demoted to debug level and periodically summarized instead.
 #[instrument(skip_all, fields(id = _id, browser = _browser, binary = _binary))]
 async fn stderr_task(
     stderr: impl AsyncRead + Unpin + Debug,
     _id: u32,
     _browser: &str,
     _binary: &str,
+    warn_lines: usize,
 ) -> std::io::Result<()> {
     let mut buf = String::new();
     let mut reader = BufReader::new(stderr);
+    let mut lines = 0usize;
+    let mut suppressed = 0usize;
+    let mut last_summary = Instant::now();
 
     loop {
         match reader.read_line(&mut buf).await {
-            Ok(0) => return Ok(()), // Closed
+            Ok(0) => break, // Closed
             Ok(_) => {
                 buf.pop();
                 // Remove newline
-                warn!("task error: {}", buf);
+                lines += 1;
+                if lines <= warn_lines {
+                    warn!("task error: {}", buf);
+                } else {
+                    debug!("task error: {}", buf);
+                    suppressed += 1;
+                    if last_summary.elapsed() >= STDERR_SUMMARY_INTERVAL {
+                        warn!("suppressed {suppressed} stderr lines");
+                        suppressed = 0;
+                        last_summary = Instant::now();
+                    }
+                }
                 buf.clear(); // Clear buffer for next message
             }
             Err(e) => return Err(e),
         }
     }
+
+    if suppressed > 0 {
+        warn!("suppressed {suppressed} stderr lines");
+    }
+
+    Ok(())
 }
@@ -0,0 +1,95 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde_json::{json, Value};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::unix::pipe;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tracing::{debug, Instrument};
+
+/// Events buffered for a slow reader, further ones are dropped
+const BUFFERED_EVENTS: usize = 256;
+
+/// Connection lifecycle events written as JSON lines to a fifo or Unix socket, if configured.
+/// Events are dropped while nothing reads them or the reader falls behind, so that sessions
+/// are never blocked by monitoring.
+#[derive(Clone, Default)]
+pub(crate) struct EventStream(Option<Sender<Value>>);
+
+impl EventStream {
+    pub fn open(path: Option<&Path>) -> Self {
+        let Some(path) = path else {
+            return Self(None);
+        };
+
+        let (sender, receiver) = mpsc::channel(BUFFERED_EVENTS);
+        tokio::spawn(write_events(path.to_owned(), receiver).in_current_span());
+        Self(Some(sender))
+    }
+
+    /// Emits `event` of session `id`, along with the fields of the `details` object
+    pub fn emit(&self, event: &str, browser: &str, id: u32, details: Value) {
+        let Some(sender) = &self.0 else {
+            return;
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let mut line = json!({"event": event, "browser": browser, "id": id, "time_ms": time});
+        if let (Value::Object(line), Value::Object(details)) = (&mut line, details) {
+            line.extend(details);
+        }
+
+        if sender.try_send(line).is_err() {
+            debug!("event stream is full, dropping {event} event of client {id}");
+        }
+    }
+}
+
+/// Writes events as they come, (re)connecting to the reader at `path` as needed
+async fn write_events(path: PathBuf, mut events: Receiver<Value>) {
+    let mut writer = None;
+    while let Some(event) = events.recv().await {
+        if writer.is_none() {
+            match connect(&path).await {
+                Ok(w) => writer = Some(w),
+                Err(e) => {
+                    debug!("dropping event, {} isn't being read: {e}", path.display());
+                    continue;
+                }
+            }
+        }
+
+        let mut line = event.to_string().into_bytes();
+        line.push(b'\n');
+        if let Some(w) = &mut writer {
+            if let Err(e) = w.write_all(&line).await {
+                debug!("event reader at {} went away: {e}", path.display());
+                writer = None;
+            }
+        }
+    }
+}
+
+async fn connect(path: &Path) -> IoResult<Box<dyn AsyncWrite + Unpin + Send>> {
+    let file_type = fs::metadata(path).await?.file_type();
+    if file_type.is_socket() {
+        Ok(Box::new(UnixStream::connect(path).await?))
+    } else if file_type.is_fifo() {
+        // Fails while the fifo has no reader, instead of waiting for one
+        Ok(Box::new(pipe::OpenOptions::new().open_sender(path)?))
+    } else {
+        Err(IoError::new(
+            ErrorKind::InvalidInput,
+            "not a fifo or Unix socket",
+        ))
+    }
+}
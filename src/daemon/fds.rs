@@ -0,0 +1,67 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::traits::*;
+use anyhow::{Context, Result};
+use std::fs::OpenOptions;
+use std::io::Error as IoError;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use tokio::process::Command;
+
+/// First file descriptor number used for passed descriptors, following stdio
+const PASS_FD_START: libc::c_int = 3;
+
+/// Opens the given paths for passing to a native binary. Unix sockets are connected
+/// to, all other paths are opened for reading and writing.
+pub fn open_pass_fds(paths: &[impl AsRef<Path>]) -> Result<Vec<OwnedFd>> {
+    paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let metadata = std::fs::metadata(path).path_context(path)?;
+            let fd = match metadata.file_type().is_socket() {
+                true => UnixStream::connect(path).map(OwnedFd::from),
+                false => OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(path)
+                    .map(OwnedFd::from),
+            };
+
+            fd.with_context(|| path.display().to_string())
+                .context("Unable to open file descriptor for native binary")
+        })
+        .collect()
+}
+
+/// Arranges for `fds` to be inherited by the child as descriptors 3, 4, ... in order.
+/// The descriptors must be kept open until the child has been spawned.
+pub fn pass_fds(command: &mut Command, fds: &[OwnedFd]) {
+    let mut raw: Vec<_> = fds.iter().map(|fd| fd.as_raw_fd()).collect();
+    let count = raw.len() as libc::c_int;
+
+    // SAFETY: the closure only performs async-signal-safe system calls and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            // Move the descriptors out of the target range first to avoid clobbering them
+            for fd in raw.iter_mut() {
+                *fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, PASS_FD_START + count);
+                if *fd < 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+
+            // Duplicates do not inherit the close-on-exec flag
+            for (target, fd) in (PASS_FD_START..).zip(raw.iter()) {
+                if libc::dup2(*fd, target) < 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
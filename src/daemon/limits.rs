@@ -0,0 +1,43 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::runtime::ManifestSettings;
+use std::io::Error as IoError;
+use tokio::process::Command;
+use tracing::debug;
+
+/// Arranges for the resource limits of `settings` to be set in the child before it executes.
+/// Limits only apply to the native binary process and are inherited by its children.
+pub fn apply_limits(command: &mut Command, settings: &ManifestSettings) {
+    let limits: Vec<_> = [
+        (libc::RLIMIT_AS, settings.memory_limit),
+        (libc::RLIMIT_CPU, settings.cpu_time_limit),
+        (libc::RLIMIT_NOFILE, settings.open_files_limit),
+    ]
+    .into_iter()
+    .filter_map(|(resource, limit)| Some((resource, limit?)))
+    .collect();
+
+    if limits.is_empty() {
+        return;
+    }
+
+    debug!("applying resource limits: {:?}", limits);
+
+    // SAFETY: the closure only performs async-signal-safe system calls and does not allocate
+    unsafe {
+        command.pre_exec(move || {
+            for (resource, limit) in &limits {
+                let rlimit = libc::rlimit {
+                    rlim_cur: *limit,
+                    rlim_max: *limit,
+                };
+                if libc::setrlimit(*resource, &rlimit) < 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+
+            Ok(())
+        });
+    }
+}
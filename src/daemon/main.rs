@@ -1,180 +1,184 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::client::ClientTaskConfig;
-use anyhow::{anyhow, bail, Context, Error, Result};
-use std::collections::HashMap;
-use std::os::fd::OwnedFd;
-use std::os::unix::net as std_net;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
-use tokio::net::UnixListener;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::env;
+use std::time::Duration;
+use tokio::select;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinSet;
-use tokio::{select, signal};
 use tokio_util::sync::CancellationToken;
-use tracing::instrument;
-use tracing::{error, info};
+use tracing::{info, instrument, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
 
 use nm_proxy::common;
-use nm_proxy::common::runtime::Settings;
-use nm_proxy::common::traits::*;
-
-mod client;
-
-#[instrument(level = "debug", ret)]
-async fn parse_sockets() -> Result<HashMap<String, OwnedFd>> {
-    Ok(sd_listen_fds::get()
-        .context("Socket parsing failed")?
-        .into_iter()
-        .map(|(name, fd)| {
-            let std_fd = fd.into_std();
-            match name {
-                None => Err(anyhow!("No name provided for fd {:?}", std_fd)),
-                Some(n) => Ok((n, std_fd)),
-            }
-        })
-        .collect::<Result<Vec<_>>>()?
-        .into_iter()
-        .collect())
-}
-
-struct ListenerConfig {
-    browser: String,
-    listener: UnixListener,
-    bin_map_arc: Arc<HashMap<String, String>>,
-    task_id_gen: Arc<AtomicU32>,
-    token: CancellationToken,
-}
-
-impl ListenerConfig {
-    #[instrument(skip_all, fields(browser = self.browser))]
-    async fn spawn_listener(self) -> Result<()> {
-        info!("listening for incoming native messaging connections");
-
-        // This will abort all nested tasks when dropped
-        let mut client_set = JoinSet::new();
-
-        loop {
-            select! {
-                _ = self.token.cancelled() => { break }
-                res = self.listener.accept() => {
-                    match res {
-                        Ok((stream, _)) => {
-                            let browser = self.browser.clone();
-                            let bin_map = self.bin_map_arc.clone();
-                            let id = self.task_id_gen.fetch_add(1, Ordering::Relaxed);
-                            let token = self.token.clone();
-                            client_set.spawn(async move {
-                                let res = ClientTaskConfig {
-                                    browser,
-                                    stream,
-                                    bin_map,
-                                    token,
-                                }
-                                .launch(id)
-                                .await;
-                                res
-                            });
-                        }
-                        Err(e) => {
-                            error!("error accepting client: {e}");
-                        }
-                    }
-                }
-            }
-        }
+use nm_proxy::common::logging::LogFilter;
+use nm_proxy::common::runtime;
+use nm_proxy::common::runtime::{LogLevel, Settings};
+use nm_proxy::daemon;
+use nm_proxy::daemon::bind::BoundSockets;
+
+const USAGE: &str = r"
+Options:
+  --debug       Log everything, including each message and session, which is slow
+  --bind        Bind the sockets in XDG_RUNTIME_DIR instead of receiving them from systemd
+  --test-spawn <manifest> [<message>]
+                Launch the native binary of app manifest <manifest>, send it <message>
+                (JSON, defaults to {}) and print its response
+  --replay <capture> [--since <seconds>]
+                Print the messages of a session captured with capture_dir, optionally
+                only those from <seconds> into the session on";
 
-        while let Some(result) = client_set.join_next().await {
-            match result {
-                Ok(Ok(_)) => (),
-                Ok(Err(e)) => Err(e).context("client task error")?,
-                Err(e) => Err(e).context("client task join failed")?,
+#[tokio::main]
+#[instrument]
+async fn main() -> Result<()> {
+    // Debugging combines with all other options
+    let mut args: Vec<_> = env::args().skip(1).collect();
+    let debug = args
+        .iter()
+        .position(|a| a == "--debug")
+        .map(|i| args.remove(i));
+    let usage = "Usage: daemon [--debug] [--bind | --test-spawn <manifest> [<message>] | \
+        --replay <capture> [--since <seconds>]]";
+    let args = args.iter().map(|a| a.as_str()).collect::<Vec<_>>();
+    let (bind, test_spawn, replay) = match args[..] {
+        [] => (false, None, None),
+        ["--bind"] => (true, None, None),
+        ["--test-spawn", manifest] => (false, Some((manifest, "{}")), None),
+        ["--test-spawn", manifest, message] => (false, Some((manifest, message)), None),
+        ["--replay", capture] => (false, None, Some((capture, "0"))),
+        ["--replay", capture, "--since", since] => (false, None, Some((capture, since))),
+        _ => bail!("{usage}\n{USAGE}"),
+    };
+
+    // Replaying only reads the capture, without settings or logging
+    if let Some((capture, since)) = replay {
+        let since = since
+            .parse()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+            .with_context(|| format!("Invalid --since {since}, expected seconds"))?;
+        for m in daemon::capture::read_capture(capture).await? {
+            if m.elapsed >= since {
+                let elapsed = m.elapsed.as_secs_f64();
+                println!("+{elapsed:.3}s {} {}", m.direction.as_str(), m.message);
             }
         }
+        return Ok(());
+    }
 
-        Ok(())
+    // Initialize the logging framework, the configured level applies once settings are loaded.
+    // Closing spans summarize each session with its byte counts and duration in debug mode.
+    let (layer, filter) = match debug {
+        Some(_) => LogFilter::forced(LevelFilter::TRACE),
+        None => LogFilter::new(LevelFilter::ERROR),
+    };
+    let span_events = match debug {
+        Some(_) => FmtSpan::CLOSE,
+        None => FmtSpan::NONE,
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(layer)
+        .with(tracing_subscriber::fmt::layer().with_span_events(span_events));
+    if let Err(e) = subscriber.try_init() {
+        eprintln!("Failed to initialize logging, continuing without: {e}");
+    }
+    if debug.is_some() {
+        warn!("debug mode logs every message and slows down the daemon, don't use it normally");
     }
-}
 
-#[tokio::main]
-#[instrument]
-async fn main() -> Result<()> {
-    // Initialize the logging framework
-    tracing_subscriber::fmt::init();
+    if let Some((manifest, message)) = test_spawn {
+        let message: Value = serde_json::from_str(message).context("Invalid test message")?;
+        let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None)?;
+        let settings = Settings::load(&runtime_dir).await?;
+        filter.configure(settings.daemon.log_level);
+
+        let spawned = daemon::probe::test_spawn(&settings, manifest, &message).await?;
+        println!(
+            "native binary {} of browser {} responded: {}",
+            spawned.binary, spawned.browser, spawned.response
+        );
+        return Ok(());
+    }
 
     // Parse sockets passed by systemd
-    let mut sockets = parse_sockets().await?;
-    if sockets.is_empty() {
-        bail!("The daemon must be launched as a systemd socket-activated service");
+    let mut sockets = daemon::named_sockets(
+        sd_listen_fds::get()
+            .context("Socket parsing failed")?
+            .into_iter()
+            .map(|(name, fd)| (name, fd.into_std())),
+    )?;
+    if sockets.is_empty() && !bind {
+        bail!(
+            "The daemon must be launched as a systemd socket-activated service, \
+            or with --bind to bind its sockets itself"
+        );
     }
 
     // Acquire the runtime directory path
     let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None)?;
+    runtime::check_runtime_dir(&runtime_dir)?;
 
     // Load runtime settings
-    let settings = Settings::load(&runtime_dir).await?;
-
-    let mut set = JoinSet::new();
-    let task_id = Arc::new(AtomicU32::new(0));
-    let token = CancellationToken::new();
+    let mut settings = Settings::load(&runtime_dir).await?;
+    filter.configure(settings.daemon.log_level);
+    if debug.is_some() {
+        settings.daemon.latency_threshold_ms = Some(0); // Logs every message with its latency
+        settings.daemon.accept_log_level = LogLevel::Info; // Logs the peer credentials
+    }
 
-    for (browser, bin_map) in settings.native_binaries {
-        // Retrieve fd from socket configuration
-        let fd = match sockets.remove(&browser) {
-            Some(fd) => fd,
-            None => {
-                return Err(anyhow!("{}: socket not found", browser).context(
-                    r"
-Expected socket from systemd, but it is absent. Check
-ListenStream/FileDescriptorName entries in socket unit(s)",
-                ));
-            }
-        };
-
-        // Construct UNIX socket listener
-        let listener =
-            UnixListener::from_std(std_net::UnixListener::from(fd)).path_context(&browser)?;
-
-        // These need to have distributed access since Tokio tasks can't be scoped
-        let bin_map_arc = Arc::new(bin_map);
-        let task_id_gen = task_id.clone();
-        let token = token.clone();
-
-        set.spawn(async move {
-            ListenerConfig {
-                browser,
-                listener,
-                bin_map_arc,
-                task_id_gen,
-                token,
-            }
-            .spawn_listener()
-            .await
-        });
+    if bind && settings.daemon.idle_exit.is_some() {
+        warn!("idle_exit is set, but without socket activation nothing starts the daemon again");
     }
 
-    // Graceful shutdown helper task
-    set.spawn(async move {
-        signal::ctrl_c()
-            .await
-            .map_err(|e| Error::from(e).context("Failed to wait for SIGINT"))
-    });
-
-    // Handle responses from tasks
-    let mut aborted = false;
-    while let Some(result) = set.join_next().await {
-        match result {
-            Ok(Ok(_)) => (),
-            Ok(Err(e)) => Err(e).context("listener task error")?,
-            Err(e) => Err(e).context("listener task join failed")?,
+    // Self-bound sockets are removed when this is dropped, after the daemon has stopped
+    let _bound = match bind {
+        true if !sockets.is_empty() => {
+            bail!("Sockets were passed by systemd, --bind can't be used with socket activation")
         }
+        true => {
+            let (bound, bound_sockets) = BoundSockets::bind(
+                &runtime_dir,
+                settings
+                    .native_binaries
+                    .keys()
+                    .map(|b| (b.as_str(), settings.daemon.socket_name(b))),
+                settings.daemon.backlog,
+            )?;
+            sockets = bound_sockets;
+            Some(bound)
+        }
+        false => None,
+    };
+
+    let token = CancellationToken::new();
+    let daemon = daemon::run(sockets, settings, token.clone());
+    tokio::pin!(daemon);
+
+    // Graceful shutdown helper tasks, systemd stops services with SIGTERM
+    let mut signals = JoinSet::new();
+    for (kind, name) in [
+        (SignalKind::interrupt(), "SIGINT"),
+        (SignalKind::terminate(), "SIGTERM"),
+        (SignalKind::quit(), "SIGQUIT"),
+    ] {
+        let mut stream = signal(kind).with_context(|| format!("Failed to listen for {name}"))?;
+        signals.spawn(async move {
+            stream.recv().await;
+            name
+        });
+    }
 
-        if !aborted {
-            aborted = true;
-            token.cancel(); // Begin graceful shutdown
+    select! {
+        res = &mut daemon => return res,
+        Some(res) = signals.join_next() => {
+            info!("received {}, shutting down", res.context("Signal task failed")?);
         }
     }
 
-    info!("graceful shutdown");
-    Ok(())
+    token.cancel(); // Begin graceful shutdown
+    daemon.await
 }
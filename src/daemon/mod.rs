@@ -0,0 +1,634 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::runtime::{DaemonSettings, LogLevel, Settings};
+use crate::common::traits::*;
+use crate::daemon::client::ClientTaskConfig;
+use crate::daemon::events::EventStream;
+use crate::daemon::persistent::HostPool;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use nix::sys::socket::getsockopt;
+#[cfg(target_os = "macos")]
+use nix::sys::socket::sockopt::LocalPeerPid;
+#[cfg(target_os = "linux")]
+use nix::sys::socket::sockopt::PeerCredentials;
+#[cfg(target_os = "macos")]
+use nix::unistd::getpeereid;
+use nix::unistd::getuid;
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::num::NonZeroUsize;
+use std::os::fd::OwnedFd;
+use std::os::unix::net as std_net;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Mutex;
+use tokio::task::{self, JoinSet};
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+use tracing::{error, info, warn};
+
+pub mod bind;
+pub mod capture;
+pub mod client;
+mod events;
+mod fds;
+mod limits;
+mod persistent;
+pub mod probe;
+
+/// Logs a message at a level configured at runtime
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)+) => {
+        match $level {
+            LogLevel::Off => (),
+            LogLevel::Error => tracing::error!($($arg)+),
+            LogLevel::Warn => tracing::warn!($($arg)+),
+            LogLevel::Info => tracing::info!($($arg)+),
+            LogLevel::Debug => tracing::debug!($($arg)+),
+            LogLevel::Trace => tracing::trace!($($arg)+),
+        }
+    };
+}
+
+/// Maps listening sockets to their names, such as those passed by systemd
+#[instrument(level = "debug", skip(fds), ret)]
+pub fn named_sockets(
+    fds: impl IntoIterator<Item = (Option<String>, OwnedFd)>,
+) -> Result<HashMap<String, OwnedFd>> {
+    let mut sockets = HashMap::new();
+    for (name, fd) in fds {
+        match name {
+            None => bail!("No name provided for fd {:?}", fd),
+            Some(n) if sockets.contains_key(&n) => bail!("{}: duplicate socket name", n),
+            Some(n) => sockets.insert(n, fd),
+        };
+    }
+
+    Ok(sockets)
+}
+
+/// Kernel limit on the socket backlog, which caps the `Backlog=` of the systemd socket unit
+fn max_backlog() -> Option<u32> {
+    std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Delay before accepting connections again after running out of resources
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Delay before restarting a failed listener, multiplied by the number of restarts so far
+const LISTENER_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Time that the connection queue must stay at its warning depth before warning about it
+const QUEUE_OVERLOAD_PERIOD: Duration = Duration::from_secs(5);
+
+/// Accepted connections waiting for a worker
+#[derive(Default)]
+struct QueueDepth {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl QueueDepth {
+    fn push(&self) {
+        let depth = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    fn pop(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+}
+
+/// Sessions in progress across all listeners, for exiting once the daemon is idle
+struct Activity {
+    sessions: AtomicUsize,
+    last_active: StdMutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            sessions: AtomicUsize::new(0),
+            last_active: StdMutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts a session as active, including while it waits for a worker, until dropped
+    fn start(self: &Arc<Self>) -> ActiveSession {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(self.clone())
+    }
+
+    /// Waits until no session has been active for `period`
+    async fn idle(&self, period: Duration) {
+        loop {
+            let idle_since = match self.sessions.load(Ordering::Relaxed) {
+                0 => *self.last_active.lock().unwrap(),
+                _ => Instant::now(),
+            };
+
+            let deadline = idle_since + period;
+            if deadline <= Instant::now() {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+struct ActiveSession(Arc<Activity>);
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        // Ending the last session starts the idle period
+        *self.0.last_active.lock().unwrap() = Instant::now();
+        self.0.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Warns once whenever the connection queue stays at `threshold` or deeper for
+/// `QUEUE_OVERLOAD_PERIOD`, meaning that the native binaries aren't keeping up
+struct OverloadMonitor {
+    threshold: usize,
+    since: Option<Instant>,
+    warned: bool,
+}
+
+impl OverloadMonitor {
+    fn check(&mut self, depth: &QueueDepth) {
+        let current = depth.current();
+        if current < self.threshold {
+            self.since = None;
+            self.warned = false;
+            return;
+        }
+
+        let since = *self.since.get_or_insert_with(Instant::now);
+        if !self.warned && since.elapsed() >= QUEUE_OVERLOAD_PERIOD {
+            warn!(
+                "{current} connections waiting for a worker for {:.1?} (peak {}), \
+                native binaries aren't keeping up, consider raising workers",
+                since.elapsed(),
+                depth.peak()
+            );
+            self.warned = true;
+        }
+    }
+}
+
+/// Sessions in flight of a failed listener, served to completion while it restarts
+type Draining = (String, Pin<Box<dyn Future<Output = Result<()>> + Send>>);
+
+/// State shared by all listeners of the daemon
+#[derive(Clone)]
+struct ListenerContext {
+    settings: Arc<DaemonSettings>,
+    task_id_gen: Arc<AtomicU32>,
+    activity: Arc<Activity>,
+    events: EventStream,
+    drains: mpsc::UnboundedSender<Draining>,
+    token: CancellationToken,
+}
+
+struct ListenerConfig {
+    browser: String,
+    listener: std_net::UnixListener,
+    bin_map_arc: Arc<HashMap<String, String>>,
+    settings: Arc<DaemonSettings>,
+    task_id_gen: Arc<AtomicU32>,
+    activity: Arc<Activity>,
+    events: EventStream,
+    drains: mpsc::UnboundedSender<Draining>,
+    token: CancellationToken,
+    start_delay: Duration,
+}
+
+impl ListenerConfig {
+    #[instrument(skip_all, fields(browser = self.browser))]
+    async fn spawn_listener(self) -> Result<()> {
+        // A restarted listener failing again right away would exhaust the restarts at once
+        select! {
+            _ = time::sleep(self.start_delay) => (),
+            _ = self.token.cancelled() => return Ok(()),
+        }
+
+        // Registering a socket that isn't listening yet would leave it unreadable for good
+        let listener = UnixListener::from_std(self.listener).path_context(&self.browser)?;
+
+        // The backlog is set by whoever bound the socket, only its upper bound is visible here
+        match max_backlog() {
+            Some(max) => info!(
+                "listening for incoming native messaging connections, \
+                backlog is capped at {max} by net.core.somaxconn"
+            ),
+            None => info!("listening for incoming native messaging connections"),
+        }
+        let allowed_uid = self
+            .settings
+            .allowed_uid
+            .unwrap_or_else(|| getuid().as_raw());
+
+        // Idle persistent native binaries of this listener
+        let hosts = Arc::new(HostPool::default());
+
+        // This will abort all nested tasks when dropped
+        let mut client_set = JoinSet::new();
+
+        // Connections are either served by a fixed number of workers or by a task each
+        let depth = Arc::new(QueueDepth::default());
+        let queue = self.settings.workers.map(|workers| {
+            let (queue, receiver) = mpsc::unbounded_channel();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for _ in 0..workers.get() {
+                client_set.spawn(client_worker(
+                    receiver.clone(),
+                    depth.clone(),
+                    self.token.clone(),
+                ));
+            }
+            queue
+        });
+
+        let mut monitor = OverloadMonitor {
+            threshold: self
+                .settings
+                .queue_warn_depth
+                .or(self.settings.workers.map(NonZeroUsize::get))
+                .unwrap_or(usize::MAX)
+                .max(1),
+            since: None,
+            warned: false,
+        };
+        let mut overload_check = time::interval(Duration::from_secs(1));
+
+        let mut failure = None;
+        loop {
+            select! {
+                _ = self.token.cancelled() => { break }
+                _ = overload_check.tick(), if queue.is_some() => monitor.check(&depth),
+                res = listener.accept() => {
+                    match res {
+                        Ok((stream, _)) => {
+                            // Only accept connections from the expected user
+                            let (peer_pid, peer_uid) = match peer_credentials(&stream) {
+                                Ok((pid, uid)) if uid == allowed_uid => (pid, uid),
+                                Ok((pid, uid)) => {
+                                    warn!("rejected client: pid {pid}, uid {uid}");
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("unable to read client credentials: {e}");
+                                    continue;
+                                }
+                            };
+
+                            let id = self.task_id_gen.fetch_add(1, Ordering::Relaxed);
+                            log_at!(
+                                self.settings.accept_log_level,
+                                "accepted client {id}: pid {peer_pid}, uid {peer_uid}"
+                            );
+                            let details = json!({"pid": peer_pid, "uid": peer_uid});
+                            self.events.emit("accepted", &self.browser, id, details);
+                            let client = ClientTaskConfig {
+                                browser: self.browser.clone(),
+                                stream,
+                                peer_pid,
+                                peer_uid,
+                                bin_map: self.bin_map_arc.clone(),
+                                settings: self.settings.clone(),
+                                hosts: hosts.clone(),
+                                events: self.events.clone(),
+                                token: self.token.clone(),
+                            };
+
+                            let session = self.activity.start();
+                            match &queue {
+                                Some(queue) => {
+                                    depth.push();
+                                    _ = queue.send((id, client, session));
+                                }
+                                None => _ = client_set.spawn(async move {
+                                    let _session = session;
+                                    client.serve(id).await
+                                }),
+                            }
+                        }
+                        // The socket itself is unusable, the listener needs to be restarted
+                        Err(e) if matches!(
+                            e.raw_os_error(),
+                            Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP)
+                        ) => {
+                            failure = Some(e);
+                            break;
+                        }
+                        Err(e) => {
+                            let hint = match e.raw_os_error() {
+                                Some(libc::EMFILE | libc::ENFILE) => {
+                                    ", the file descriptor limit has been reached"
+                                }
+                                Some(libc::ENOBUFS | libc::ENOMEM) => {
+                                    ", the system is out of memory for sockets"
+                                }
+                                _ => "",
+                            };
+                            error!("error accepting client: {e}{hint}");
+
+                            // Exhausted resources make accept fail immediately, don't spin
+                            if !hint.is_empty() {
+                                time::sleep(ACCEPT_RETRY_DELAY).await;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        if queue.is_some() {
+            info!("connection queue depth peaked at {}", depth.peak());
+        }
+
+        drop(queue); // Stops idle workers
+        let drain = async move {
+            while let Some(result) = client_set.join_next().await {
+                match result {
+                    Ok(Ok(_)) => (),
+                    Ok(Err(e)) => Err(e).context("client task error")?,
+                    Err(e) => Err(e).context("client task join failed")?,
+                }
+            }
+
+            hosts.terminate_all().await;
+            Ok(())
+        };
+
+        match failure {
+            None => drain.await,
+            Some(e) => {
+                // Sessions in flight keep being served while the listener restarts
+                _ = self.drains.send((self.browser, Box::pin(drain)));
+                Err(e).context("Unable to accept connections")
+            }
+        }
+    }
+}
+
+/// Serves queued connections one at a time, returning the first error once the queue closes
+async fn client_worker(
+    receiver: Arc<Mutex<UnboundedReceiver<(u32, ClientTaskConfig, ActiveSession)>>>,
+    depth: Arc<QueueDepth>,
+    token: CancellationToken,
+) -> Result<()> {
+    let mut result = Ok(());
+    loop {
+        let next = receiver.lock().await.recv().await;
+        let Some((id, client, _session)) = next else {
+            return result;
+        };
+        depth.pop();
+
+        // Connections still queued at shutdown are dropped without serving
+        if !token.is_cancelled() {
+            let res = client.serve(id).await;
+            result = result.and(res);
+        }
+    }
+}
+
+/// PID and UID of the peer process of a connected socket
+#[cfg(target_os = "linux")]
+fn peer_credentials(stream: &UnixStream) -> nix::Result<(i32, u32)> {
+    getsockopt(stream, PeerCredentials).map(|c| (c.pid(), c.uid()))
+}
+
+/// PID and UID of the peer process of a connected socket
+#[cfg(target_os = "macos")]
+fn peer_credentials(stream: &UnixStream) -> nix::Result<(i32, u32)> {
+    let pid = getsockopt(stream, LocalPeerPid)?;
+    let (uid, _) = getpeereid(stream)?;
+    Ok((pid, uid.as_raw()))
+}
+
+/// Listening socket of a browser, which can be served again after the listener fails
+struct Listener {
+    browser: String,
+    fd: OwnedFd,
+    bin_map_arc: Arc<HashMap<String, String>>,
+    restarts: u32,
+}
+
+impl Listener {
+    fn spawn(&self, set: &mut JoinSet<Result<()>>, context: &ListenerContext) -> Result<task::Id> {
+        // Construct UNIX socket listener from a duplicate, keeping the fd for restarts
+        let fd = self
+            .fd
+            .try_clone()
+            .with_context(|| format!("{}: unable to duplicate socket", self.browser))?;
+        let listener = std_net::UnixListener::from(fd);
+        listener
+            .set_nonblocking(true)
+            .with_context(|| format!("{}: unable to configure socket", self.browser))?;
+
+        // These need to have distributed access since Tokio tasks can't be scoped
+        let context = context.clone();
+        let config = ListenerConfig {
+            browser: self.browser.clone(),
+            listener,
+            bin_map_arc: self.bin_map_arc.clone(),
+            settings: context.settings,
+            task_id_gen: context.task_id_gen,
+            activity: context.activity,
+            events: context.events,
+            drains: context.drains,
+            token: context.token,
+            start_delay: LISTENER_RESTART_DELAY * self.restarts,
+        };
+
+        Ok(set.spawn(config.spawn_listener()).id())
+    }
+}
+
+/// Serves native messaging connections on the given named sockets until `token` is
+/// cancelled, or any of the listeners exits, which triggers a graceful shutdown
+pub async fn run(
+    mut sockets: HashMap<String, OwnedFd>,
+    settings: Settings,
+    token: CancellationToken,
+) -> Result<()> {
+    if settings.native_binaries.is_empty() {
+        bail!(
+            r"No native binaries are registered, the daemon has nothing to serve.
+Place app manifests in the nm-proxy manifest directory and re-run setup"
+        );
+    }
+
+    let daemon_settings = Arc::new(settings.daemon);
+    let mut set = JoinSet::new();
+    let mut listeners = HashMap::new();
+    let mut draining = HashMap::new();
+    let (drains, mut drain_queue) = mpsc::unbounded_channel();
+    let activity = Arc::new(Activity::new());
+    let context = ListenerContext {
+        settings: daemon_settings.clone(),
+        task_id_gen: Arc::new(AtomicU32::new(0)),
+        activity: activity.clone(),
+        events: EventStream::open(daemon_settings.event_stream.as_deref()),
+        drains,
+        token: token.clone(),
+    };
+
+    // Socket names as received, for spotting mismatches with the configured browsers
+    let mut provided: Vec<_> = sockets.keys().cloned().collect();
+    provided.sort();
+
+    for (browser, bin_map) in settings.native_binaries {
+        // Retrieve fd from socket configuration
+        let socket = daemon_settings.socket_name(&browser);
+        let fd = match sockets.remove(socket) {
+            Some(fd) => fd,
+            None => {
+                let hint = match provided.iter().find(|n| n.eq_ignore_ascii_case(socket)) {
+                    Some(name) => format!(", did you mean {name:?}?"),
+                    None => String::new(),
+                };
+                let named = match socket == browser {
+                    true => String::new(),
+                    false => format!(" named {socket}"),
+                };
+                let received = match provided.is_empty() {
+                    true => "none".into(),
+                    false => provided.join(", "),
+                };
+                return Err(anyhow!(
+                    "{browser}: socket{named} not found, received sockets named: {received}{hint}"
+                )
+                .context(
+                    r"
+Expected socket from systemd, but it is absent. Check
+ListenStream/FileDescriptorName entries in socket unit(s)",
+                ));
+            }
+        };
+
+        let listener = Listener {
+            browser,
+            fd,
+            bin_map_arc: Arc::new(bin_map),
+            restarts: 0,
+        };
+        let id = listener.spawn(&mut set, &context)?;
+        listeners.insert(id, listener);
+    }
+
+    for browser in sockets.keys() {
+        info!("{browser}: no native binaries registered, ignoring socket");
+    }
+
+    // Graceful shutdown must not take longer than the deadline after cancellation
+    let timeout = Duration::from_secs(daemon_settings.shutdown_timeout);
+    let deadline = async {
+        token.cancelled().await;
+        time::sleep(timeout).await;
+    };
+    tokio::pin!(deadline);
+
+    // Socket activation starts the daemon again on the next connection after exiting idle
+    let idle_exit = daemon_settings.idle_exit.map(Duration::from_secs);
+    let idle = async {
+        match idle_exit {
+            Some(period) => activity.idle(period).await,
+            None => future::pending().await,
+        }
+    };
+    tokio::pin!(idle);
+
+    // Handle responses from tasks
+    let mut aborted = false;
+    loop {
+        let result = select! {
+            result = set.join_next_with_id() => match result {
+                Some(r) => r,
+                None => break,
+            },
+            Some((browser, drain)) = drain_queue.recv() => {
+                draining.insert(set.spawn(drain).id(), browser);
+                continue;
+            }
+            _ = &mut idle, if !token.is_cancelled() => {
+                info!("no sessions for {:?}, exiting while idle", idle_exit.unwrap_or_default());
+                token.cancel(); // Begin graceful shutdown
+                continue;
+            }
+            _ = &mut deadline => {
+                let mut running = listeners.into_values().map(|l| l.browser).collect::<Vec<_>>();
+                running.extend(draining.into_values());
+                running.sort();
+                running.dedup();
+                bail!(
+                    "Graceful shutdown timed out after {timeout:?}, listeners still running: {}",
+                    running.join(", ")
+                );
+            }
+        };
+
+        let (id, error) = match result {
+            Ok((id, Ok(_))) => (id, None),
+            Ok((id, Err(e))) => (id, Some(e.context("listener task error"))),
+            Err(e) => (
+                e.id(),
+                Some(Error::from(e).context("listener task join failed")),
+            ),
+        };
+
+        // Sessions of a failed listener don't take the daemon down once they have ended
+        if let Some(browser) = draining.remove(&id) {
+            if let Some(e) = error {
+                error!("{browser}: {e:#}");
+            }
+            continue;
+        }
+
+        let mut listener = listeners.remove(&id).unwrap();
+        if let Some(e) = error {
+            // Listeners only exit by themselves when failing, restart them if allowed
+            if token.is_cancelled() || listener.restarts >= daemon_settings.listener_restarts {
+                return Err(e);
+            }
+
+            listener.restarts += 1;
+            warn!(
+                "{}: restarting listener ({}/{}) after unexpected exit: {e:#}",
+                listener.browser, listener.restarts, daemon_settings.listener_restarts
+            );
+            let id = listener.spawn(&mut set, &context)?;
+            listeners.insert(id, listener);
+            continue;
+        }
+
+        if !aborted {
+            aborted = true;
+            token.cancel(); // Begin graceful shutdown
+        }
+    }
+
+    info!("graceful shutdown");
+    Ok(())
+}
@@ -0,0 +1,133 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::keepalive::Link;
+use crate::common::FrameCodec;
+use crate::daemon::client::terminate_child;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWrite, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+use tokio::select;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn, Instrument};
+
+/// Manifest name and browser arguments that a persistent native binary was launched with
+pub(crate) type HostKey = (String, Vec<String>);
+
+/// Native binary that is kept running across browser sessions
+pub(crate) struct PersistentHost {
+    pub binary: String,
+    pub child: Child,
+    pub stdin: ChildStdin,
+    pub stdout: BufReader<ChildStdout>,
+}
+
+impl PersistentHost {
+    pub async fn terminate(mut self) {
+        if let Err(e) = terminate_child(&mut self.child, &self.binary).await {
+            warn!("{e:#}");
+        }
+    }
+}
+
+/// Idle persistent native binaries of a listener waiting for a browser to reattach
+#[derive(Default)]
+pub(crate) struct HostPool {
+    hosts: Mutex<HashMap<HostKey, (u64, PersistentHost)>>,
+    generation: AtomicU64,
+}
+
+impl HostPool {
+    /// Takes the idle native binary for `key`, if it is still running
+    pub fn take(&self, key: &HostKey) -> Option<PersistentHost> {
+        let (_, mut host) = self.hosts.lock().unwrap().remove(key)?;
+        match host.child.try_wait() {
+            Ok(None) => Some(host),
+            _ => None, // Exited while idle
+        }
+    }
+
+    /// Terminates all idle native binaries
+    pub async fn terminate_all(&self) {
+        let hosts: Vec<_> = self.hosts.lock().unwrap().drain().collect();
+        for (_, (_, host)) in hosts {
+            host.terminate().await;
+        }
+    }
+
+    /// Parks `host` for reattaching, terminating it if that doesn't happen within `ttl`
+    /// or the daemon shuts down
+    pub fn park(
+        self: &Arc<Self>,
+        key: HostKey,
+        host: PersistentHost,
+        ttl: Duration,
+        token: CancellationToken,
+    ) {
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed);
+        let replaced = self
+            .hosts
+            .lock()
+            .unwrap()
+            .insert(key.clone(), (generation, host));
+
+        let pool = self.clone();
+        tokio::spawn(
+            async move {
+                // A concurrent session for the same key went idle first
+                if let Some((_, host)) = replaced {
+                    host.terminate().await;
+                }
+
+                select! {
+                    _ = time::sleep(ttl) => debug!("persistent native binary idle for {ttl:?}"),
+                    _ = token.cancelled() => (),
+                }
+
+                let expired = {
+                    let mut hosts = pool.hosts.lock().unwrap();
+                    match hosts.get(&key) {
+                        Some((g, _)) if *g == generation => hosts.remove(&key),
+                        _ => None, // Reattached in the meantime
+                    }
+                };
+
+                if let Some((_, host)) = expired {
+                    host.terminate().await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Forwards frames from the output of a persistent native binary until `detach` is
+/// cancelled, which only takes effect between frames. Returns the number of bytes
+/// forwarded and whether the native binary closed its output.
+pub(crate) async fn forward_host_output(
+    stdout: &mut BufReader<ChildStdout>,
+    link: &Link<impl AsyncWrite + Unpin>,
+    (max_size, codec): (u32, FrameCodec),
+    detach: &CancellationToken,
+) -> std::io::Result<(u64, bool)> {
+    let mut total = 0;
+
+    loop {
+        // Waiting for the start of a frame is cancel-safe, as no data is consumed
+        select! {
+            biased;
+            _ = detach.cancelled() => return Ok((total, false)),
+            res = stdout.fill_buf() => if res?.is_empty() {
+                return Ok((total, true)); // Closed
+            },
+        }
+
+        match link.forward_frame_from(stdout, (max_size, codec)).await? {
+            Some(n) => total += n,
+            None => return Ok((total, true)),
+        }
+    }
+}
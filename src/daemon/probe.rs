@@ -0,0 +1,100 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::common::constants::*;
+use crate::common::runtime::{Settings, StderrMode};
+use crate::common::{recv_nm_object, send_nm_object, HandshakeMessage};
+use crate::daemon::client::{spawn_binary, terminate_child};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use tokio::time::{self, Duration};
+
+/// Time allowed for the native binary to respond to the test message
+const TEST_SPAWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Native binary that started and responded to the test message
+#[derive(Debug)]
+pub struct TestSpawn {
+    pub browser: String,
+    pub binary: String,
+    pub response: Value,
+}
+
+/// Launches the native binary of app manifest `manifest` the way a browser session would,
+/// sends it `message` and waits for a response. Browsers are tried in alphabetical order.
+pub async fn test_spawn(settings: &Settings, manifest: &str, message: &Value) -> Result<TestSpawn> {
+    let mut registered: Vec<_> = settings
+        .native_binaries
+        .iter()
+        .filter_map(|(browser, binaries)| Some((browser, binaries.get(manifest)?)))
+        .collect();
+    registered.sort();
+    let Some((browser, binary)) = registered.first() else {
+        return Err(anyhow!(
+            "No native binary is registered for app manifest {manifest}, has setup deployed it?"
+        ));
+    };
+
+    // No browser is involved, so there are no arguments to pass
+    let handshake = HandshakeMessage {
+        manifest_name: manifest.into(),
+        args: vec![],
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: MAX_MESSAGE_SIZE,
+        compression: false,
+        keepalive: false,
+        client_version: None,
+        reconnect: false,
+        terminate: false,
+        profile: None,
+    };
+
+    // Show the stderr of the native binary right away
+    let mut manifest_settings = settings
+        .daemon
+        .manifests
+        .get(manifest)
+        .cloned()
+        .unwrap_or_default();
+    manifest_settings.stderr = Some(StderrMode::Inherit);
+
+    let mut child = spawn_binary(
+        binary,
+        &handshake,
+        &manifest_settings,
+        &settings.daemon,
+        browser,
+    )
+    .with_context(|| format!("Failed to launch native binary {binary}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let exchange = async {
+        send_nm_object(&mut stdin, message).await?;
+        recv_nm_object(&mut stdout).await
+    };
+    let result = match time::timeout(TEST_SPAWN_TIMEOUT, exchange).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(e)) => {
+            // A native binary that closed its output has most likely exited
+            let exited = time::timeout(Duration::from_millis(200), child.wait()).await;
+            match exited {
+                Ok(Ok(status)) => {
+                    Err(e.context(format!("Native binary {binary} exited: {status}")))
+                }
+                _ => Err(e.context(format!("Native binary {binary} sent an invalid response"))),
+            }
+        }
+        Err(_) => Err(anyhow!(
+            "Native binary {binary} didn't respond within {TEST_SPAWN_TIMEOUT:?}"
+        )),
+    };
+
+    drop(stdin);
+    terminate_child(&mut child, binary).await?;
+    Ok(TestSpawn {
+        browser: browser.to_string(),
+        binary: binary.to_string(),
+        response: result?,
+    })
+}
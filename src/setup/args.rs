@@ -0,0 +1,65 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{anyhow, bail, Result};
+use std::env;
+use std::path::PathBuf;
+
+const USAGE: &str = r"
+Options:
+  --force       Replace all deployed app manifests and proxy clients unconditionally
+  --init        Write a starter configuration for the installed Flatpak browsers, with
+                --force replacing an existing one
+  --incremental Only redeploy app manifests that changed since the previous run
+  --diagnose    Check that the Flatpak overrides expose the socket of each browser
+  --json        Print the result as a JSON document instead of logging progress
+  --print-config
+                Print the effective configuration after expansion and resolution
+  --validate-config
+                Check the configuration without deploying anything, XDG_RUNTIME_DIR is optional
+  --check-manifest <path>
+                Validate an app manifest and print what it would be deployed as";
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub force: bool,
+    pub init: bool,
+    pub incremental: bool,
+    pub diagnose: bool,
+    pub json: bool,
+    pub print_config: bool,
+    pub validate_config: bool,
+    pub check_manifest: Option<PathBuf>,
+}
+
+pub fn parse_args() -> Result<Args> {
+    let mut args = env::args();
+    let invocation_path = args
+        .next()
+        .ok_or(anyhow!("Unable to acquire invocation path"))?;
+
+    let usage = || anyhow!("Usage: {} [options]\n{}", invocation_path, USAGE);
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--force" => parsed.force = true,
+            "--init" => parsed.init = true,
+            "--incremental" => parsed.incremental = true,
+            "--diagnose" => parsed.diagnose = true,
+            "--json" => parsed.json = true,
+            "--print-config" => parsed.print_config = true,
+            "--validate-config" => parsed.validate_config = true,
+            "--check-manifest" => {
+                parsed.check_manifest = Some(args.next().ok_or_else(usage)?.into())
+            }
+            _ => return Err(usage()),
+        }
+    }
+
+    // These print their own plain text output, which would corrupt the JSON document
+    if parsed.json && (parsed.print_config || parsed.check_manifest.is_some()) {
+        bail!("--json can't be combined with --print-config or --check-manifest");
+    }
+
+    Ok(parsed)
+}
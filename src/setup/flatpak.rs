@@ -0,0 +1,137 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{bail, Context, Error, Result};
+use ini::Error::Io;
+use ini::Ini;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::{info, instrument, warn};
+
+use nm_proxy::common;
+use nm_proxy::common::config::Config;
+
+/// Exposes the sockets of all browsers to their Flatpak sandboxes, returning the paths of
+/// the written override files. Failing override files are skipped and their errors
+/// collected into `failures`.
+#[instrument(level = "trace", skip_all)]
+pub async fn configure_overrides(
+    config: &Config,
+    failures: &mut Vec<Error>,
+) -> Result<Vec<PathBuf>> {
+    // Browsers with the same app ID share an override file
+    let mut overrides = BTreeMap::<_, Vec<_>>::new();
+    for (browser, path) in config.override_paths()? {
+        overrides.entry(path).or_default().push(browser);
+    }
+
+    let mut written = Vec::new();
+    for (path, mut browsers) in overrides {
+        browsers.sort();
+        match configure_overrides_file(&browsers, &path).await {
+            Ok(()) => written.push(path),
+            Err(e) => failures.push(e),
+        }
+    }
+
+    Ok(written)
+}
+
+/// Configures the sockets of all `browsers` sharing the Flatpak app ID of the override file
+#[instrument(skip(path), fields(path = %path.as_ref().display()))]
+async fn configure_overrides_file(browsers: &[&String], path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let names = browsers
+        .iter()
+        .map(|b| b.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut ini = match Ini::load_from_file(path) {
+        Ok(i) => i,
+        Err(Io(e)) if e.kind() == ErrorKind::NotFound => Ini::new(),
+        result @ Err(_) => result
+            .with_context(|| path.display().to_string())
+            .with_context(|| format!("Unable to read Flatpak overrides for {names}"))?,
+    };
+
+    for browser in browsers {
+        set_socket_path_override(browser, &mut ini);
+    }
+
+    ini.write_to_file(path)
+        .with_context(|| path.display().to_string())
+        .with_context(|| format!("Unable to update Flatpak overrides for {names}"))?;
+
+    Ok(())
+}
+
+/// Flatpak filesystem override entry exposing the socket of the given browser
+fn socket_override_entry(browser: &str) -> String {
+    format!("xdg-run/{}", common::socket_file_name(browser))
+}
+
+fn filesystems_override(config: &Ini) -> &str {
+    config
+        .section(Some("Context"))
+        .and_then(|s| s.get("filesystems"))
+        .unwrap_or("")
+}
+
+fn socket_path_configured(browser: &str, config: &Ini) -> bool {
+    let socket_path = socket_override_entry(browser);
+    filesystems_override(config)
+        .split(';')
+        .any(|e| e == socket_path)
+}
+
+#[instrument(level = "trace", skip(config))]
+fn set_socket_path_override(browser: &str, config: &mut Ini) {
+    if socket_path_configured(browser, config) {
+        return; // Already configured
+    }
+
+    let socket_path = socket_override_entry(browser);
+    let filesystems = filesystems_override(config).to_owned();
+    config.with_section(Some("Context")).set(
+        "filesystems",
+        match &*filesystems {
+            "" => socket_path,
+            s => format!("{};{socket_path}", s.trim_end_matches(';')),
+        },
+    );
+}
+
+/// Reports browsers whose Flatpak overrides don't expose their socket to the sandbox
+#[instrument(level = "trace", skip_all)]
+pub async fn diagnose(config: &Config) -> Result<()> {
+    let mut misconfigured = Vec::new();
+    for (browser, path) in config.override_paths()? {
+        let ini = match Ini::load_from_file(&path) {
+            Ok(i) => i,
+            Err(Io(e)) if e.kind() == ErrorKind::NotFound => Ini::new(),
+            result @ Err(_) => result
+                .with_context(|| path.display().to_string())
+                .with_context(|| format!("Unable to read Flatpak overrides for {browser}"))?,
+        };
+
+        let entry = socket_override_entry(browser);
+        if socket_path_configured(browser, &ini) {
+            info!("{browser}: {entry} is exposed by {}", path.display());
+        } else {
+            warn!("{browser}: {entry} is missing from {}", path.display());
+            misconfigured.push(browser.as_str());
+        }
+    }
+
+    if !misconfigured.is_empty() {
+        misconfigured.sort();
+        bail!(
+            "Flatpak overrides are missing sockets for {}, re-run setup to fix",
+            misconfigured.join(", ")
+        );
+    }
+
+    info!("all Flatpak overrides expose their sockets");
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, info};
+
+use nm_proxy::common::constants::*;
+
+/// App manifest deployed from a source manifest into an NMH directory
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)] // Strict mode
+pub struct IndexEntry {
+    nmh_dir: PathBuf,
+    source: PathBuf,
+    source_hash: u64,
+    deployed_hash: u64,
+    pub file_name: String,
+    pub binary: String,
+}
+
+/// Deployed app manifests of the previous setup run, for only redeploying the ones whose
+/// source changed in incremental mode
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)] // Strict mode
+pub struct DeploymentIndex {
+    /// Hash of the configuration and setup version that the manifests were deployed with
+    fingerprint: u64,
+    entries: Vec<IndexEntry>,
+}
+
+/// FNV-1a, stable across builds unlike the hashers of the standard library
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl DeploymentIndex {
+    pub fn new(config: &[u8]) -> Self {
+        Self {
+            fingerprint: hash(&[env!("CARGO_PKG_VERSION").as_bytes(), config].concat()),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Loads the index of the previous run, unless it is missing, unreadable or was
+    /// created with a different configuration
+    pub async fn load(state_dir: impl AsRef<Path>, config: &[u8]) -> Option<Self> {
+        let path = state_dir.as_ref().join(DEPLOYMENT_INDEX_FILE_NAME);
+        let index: Self = match fs::read(&path).await.map(|c| serde_json::from_slice(&c)) {
+            Ok(Ok(index)) => index,
+            Ok(Err(e)) => {
+                info!("ignoring invalid deployment index {}: {e}", path.display());
+                return None;
+            }
+            Err(e) => {
+                debug!("no deployment index at {}: {e}", path.display());
+                return None;
+            }
+        };
+
+        match index.fingerprint == Self::new(config).fingerprint {
+            true => Some(index),
+            false => {
+                info!("configuration changed since the last deployment");
+                None
+            }
+        }
+    }
+
+    /// Returns the previous deployment of `source` into `nmh_dir` if neither the source
+    /// nor the deployed app manifest have changed since
+    pub async fn unchanged(
+        &self,
+        nmh_dir: &Path,
+        source: &Path,
+        source_hash: u64,
+    ) -> Option<&IndexEntry> {
+        let entry = self
+            .entries
+            .iter()
+            .find(|e| e.nmh_dir == nmh_dir && e.source == source && e.source_hash == source_hash)?;
+
+        let deployed = fs::read(nmh_dir.join(&entry.file_name)).await.ok()?;
+        (hash(&deployed) == entry.deployed_hash).then_some(entry)
+    }
+
+    /// Records the deployment of `source` into `nmh_dir` as `file_name`
+    pub async fn record(
+        &mut self,
+        nmh_dir: &Path,
+        source: &Path,
+        source_hash: u64,
+        (file_name, binary): &(String, String),
+    ) -> Result<()> {
+        let path = nmh_dir.join(file_name);
+        let deployed = fs::read(&path)
+            .await
+            .with_context(|| path.display().to_string())
+            .context("Unable to read deployed app manifest")?;
+
+        self.entries.push(IndexEntry {
+            nmh_dir: nmh_dir.into(),
+            source: source.into(),
+            source_hash,
+            deployed_hash: hash(&deployed),
+            file_name: file_name.clone(),
+            binary: binary.clone(),
+        });
+        Ok(())
+    }
+
+    /// Saves the index into `state_dir`, which is created if needed. Unlike the runtime
+    /// directory, it persists across reboots like the deployed manifests.
+    pub async fn save(&self, state_dir: impl AsRef<Path>) -> Result<()> {
+        let path = state_dir.as_ref().join(DEPLOYMENT_INDEX_FILE_NAME);
+        fs::create_dir_all(&state_dir)
+            .await
+            .with_context(|| state_dir.as_ref().display().to_string())
+            .context("Unable to create state directory")?;
+        fs::write(&path, serde_json::to_vec(self)?)
+            .await
+            .with_context(|| path.display().to_string())
+            .context("Unable to save deployment index")
+    }
+}
@@ -0,0 +1,159 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{bail, Context, Result};
+use std::io::ErrorKind;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, instrument, warn};
+
+use nm_proxy::common::config;
+use nm_proxy::common::constants::*;
+
+/// Flatpak browser recognized by its app ID, with the NMH directory inside it
+struct KnownBrowser {
+    name: &'static str,
+    app_id: &'static str,
+    nmh_dir: &'static str,
+}
+
+const KNOWN_BROWSERS: &[KnownBrowser] = &[
+    KnownBrowser {
+        name: "firefox",
+        app_id: "org.mozilla.firefox",
+        nmh_dir: ".mozilla/native-messaging-hosts",
+    },
+    KnownBrowser {
+        name: "librewolf",
+        app_id: "io.gitlab.librewolf-community",
+        nmh_dir: ".librewolf/native-messaging-hosts",
+    },
+    KnownBrowser {
+        name: "floorp",
+        app_id: "one.ablaze.floorp",
+        nmh_dir: ".floorp/native-messaging-hosts",
+    },
+    KnownBrowser {
+        name: "zen",
+        app_id: "app.zen_browser.zen",
+        nmh_dir: ".zen/native-messaging-hosts",
+    },
+    KnownBrowser {
+        name: "chromium",
+        app_id: "org.chromium.Chromium",
+        nmh_dir: ".config/chromium/NativeMessagingHosts",
+    },
+    KnownBrowser {
+        name: "ungoogled-chromium",
+        app_id: "io.github.ungoogled_software.ungoogled_chromium",
+        nmh_dir: ".config/chromium/NativeMessagingHosts",
+    },
+    KnownBrowser {
+        name: "chrome",
+        app_id: "com.google.Chrome",
+        nmh_dir: ".config/google-chrome/NativeMessagingHosts",
+    },
+    KnownBrowser {
+        name: "brave",
+        app_id: "com.brave.Browser",
+        nmh_dir: ".config/BraveSoftware/Brave-Browser/NativeMessagingHosts",
+    },
+    KnownBrowser {
+        name: "vivaldi",
+        app_id: "com.vivaldi.Vivaldi",
+        nmh_dir: ".config/vivaldi/NativeMessagingHosts",
+    },
+    KnownBrowser {
+        name: "edge",
+        app_id: "com.microsoft.Edge",
+        nmh_dir: ".config/microsoft-edge/NativeMessagingHosts",
+    },
+];
+
+/// Known browsers whose Flatpak app directory exists in `base_dir`
+async fn detect_browsers(base_dir: &Path) -> Vec<&'static KnownBrowser> {
+    let mut detected = Vec::new();
+    for browser in KNOWN_BROWSERS {
+        match fs::metadata(base_dir.join(browser.app_id)).await {
+            Ok(m) if m.is_dir() => detected.push(browser),
+            _ => debug!(
+                "browser {} not found in {}",
+                browser.name,
+                base_dir.display()
+            ),
+        }
+    }
+    detected
+}
+
+/// Starter configuration for the `browsers`, with a placeholder proxy client path
+fn starter_config(browsers: &[&KnownBrowser]) -> String {
+    let mut config = String::from(
+        "# Generated by `setup --init`, see the README for all options.\n\
+        # Set proxy_client to the path of the proxy client binary, then run setup again.\n\
+        \n\
+        [daemon]\n\
+        proxy_client = \"~/path/to/client\"\n",
+    );
+    for b in browsers {
+        config.push_str(&format!(
+            "\n[browsers.{}]\napp_id = \"{}\"\nnmh_dir = \"{}\"\n",
+            b.name, b.app_id, b.nmh_dir
+        ));
+    }
+    config
+}
+
+/// Writes a starter configuration for the Flatpak browsers installed on this machine,
+/// replacing an existing configuration only if `force` is set
+#[instrument(level = "info", skip_all)]
+pub async fn init_config(force: bool) -> Result<()> {
+    let base_dir = config::default_nmh_base_dir()?;
+    let browsers = detect_browsers(&base_dir).await;
+    match browsers.is_empty() {
+        true => warn!(
+            "no known Flatpak browsers found in {}, add them to the configuration by hand",
+            base_dir.display()
+        ),
+        false => {
+            let names = browsers.iter().map(|b| b.name).collect::<Vec<_>>();
+            info!("detected browsers: {}", names.join(", "));
+        }
+    }
+
+    let dir = config::config_dir()?;
+    fs::create_dir_all(&dir)
+        .await
+        .with_context(|| dir.display().to_string())
+        .context("Unable to create configuration directory")?;
+    let path = config::form_config_path().await?.join(CONFIG_FILE);
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(!force)
+        .create(true)
+        .truncate(true)
+        .open(&path)
+        .await;
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => bail!(
+            "{} already exists, not replacing it unless --force is given",
+            path.display()
+        ),
+        Err(e) => Err(e)
+            .with_context(|| path.display().to_string())
+            .context("Unable to create configuration file")?,
+    };
+    file.write_all(starter_config(&browsers).as_bytes())
+        .await
+        .with_context(|| path.display().to_string())
+        .context("Unable to write configuration file")?;
+
+    info!(
+        "wrote starter configuration to {}, set proxy_client in it and run setup again",
+        path.display()
+    );
+    Ok(())
+}
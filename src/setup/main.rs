@@ -1,36 +1,65 @@
 // (c) Dennis Marttinen 2023
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use anyhow::{bail, Context, Result};
-use ini::Error::Io;
-use ini::Ini;
+use anyhow::{anyhow, bail, Context, Error, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::unistd::getuid;
 use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File as StdFile, OpenOptions};
+use std::future::Future;
 use std::io::ErrorKind;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tokio::fs;
-use tokio::fs::DirEntry;
+use tokio::fs::ReadDir;
 use tokio::io::AsyncReadExt;
-use tracing::{debug, info, instrument};
+use tokio::time;
+use tracing::{debug, info, instrument, warn};
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::format::FmtSpan;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::prelude::*;
 
 use nm_proxy::common;
 use nm_proxy::common::config;
-use nm_proxy::common::config::Config;
+use nm_proxy::common::config::{ClientDeployment, Config};
 use nm_proxy::common::constants::*;
+use nm_proxy::common::logging::LogFilter;
+use nm_proxy::common::manifest;
+use nm_proxy::common::runtime;
 use nm_proxy::common::runtime::{NativeBinaryMap, Settings};
 use nm_proxy::common::traits::*;
 
+mod args;
+#[cfg(target_os = "linux")]
+mod flatpak;
 mod help;
+mod index;
+#[cfg(target_os = "linux")]
+mod init;
+mod report;
 
 use help::ManifestHelpContext;
+use index::{DeploymentIndex, IndexEntry};
+use report::{BrowserReport, Report, WarningCollector};
 
+/// Creates the NMH directory, along with any missing parents unless `require_parent` is set
 #[instrument(skip(nmh_dir), fields(browser = _browser, nmh_dir = %nmh_dir.as_ref().display()))]
-async fn create_nmh_dir(_browser: &str, nmh_dir: impl AsRef<Path>) -> Result<()> {
+async fn create_nmh_dir(
+    _browser: &str,
+    nmh_dir: impl AsRef<Path>,
+    require_parent: bool,
+) -> Result<()> {
     let nmh_dir = nmh_dir.as_ref();
 
-    match fs::create_dir(nmh_dir).await {
+    let result = match require_parent {
+        true => fs::create_dir(nmh_dir).await,
+        false => fs::create_dir_all(nmh_dir).await,
+    };
+    match result {
         Ok(_) => (),
         Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
         result @ Err(_) => result
@@ -46,160 +75,356 @@ async fn install_proxy_client(
     browser: &str,
     nmh_dir: impl AsRef<Path>,
     config: &Config,
+    force: bool,
 ) -> Result<()> {
     let nmh_dir = nmh_dir.as_ref();
-    let proxy_client_src = config.proxy_client_path();
+    let proxy_client_src = config.proxy_client_path(Some(browser));
     let proxy_client_dest = nmh_dir.join(PROXY_CLIENT_BIN);
+    if force {
+        remove_deployed(&proxy_client_dest).await?;
+    } else {
+        // Tell whether re-running setup, e.g. after an upgrade, actually updated the client
+        match deployed_copy(proxy_client_src, &proxy_client_dest).await {
+            Some(true) => {
+                info!("proxy client {} is up to date", proxy_client_src.display());
+                return Ok(());
+            }
+            Some(false) => info!("replacing outdated proxy client"),
+            None => (),
+        }
+    }
 
-    fs::copy(&proxy_client_src, &proxy_client_dest)
-        .await
-        .with_context(|| {
-            format!(
-                "{} -> {}",
-                proxy_client_src.display(),
-                proxy_client_dest.display()
-            )
-        })
-        .with_context(|| format!("{}: unable to copy proxy client", browser))?;
+    info!("deploying proxy client {}", proxy_client_src.display());
+
+    retry_transient(config.write_retries(), &proxy_client_dest, || {
+        fs::copy(proxy_client_src, &proxy_client_dest)
+    })
+    .await
+    .with_context(|| {
+        format!(
+            "{} -> {}",
+            proxy_client_src.display(),
+            proxy_client_dest.display()
+        )
+    })
+    .with_context(|| format!("{}: unable to copy proxy client", browser))?;
 
     Ok(())
 }
 
-#[instrument(skip(path), fields(path = %path.as_ref().display()))]
-async fn configure_flatpak_overrides(browser: &str, path: impl AsRef<Path>) -> Result<()> {
-    let path = path.as_ref();
-    let mut ini = match Ini::load_from_file(&path) {
-        Ok(i) => i,
-        Err(Io(e)) if e.kind() == ErrorKind::NotFound => Ini::new(),
-        result @ Err(_) => result
-            .with_context(|| path.display().to_string())
-            .with_context(|| format!("Unable to read Flatpak overrides for {browser}"))?,
+/// Tells whether the deployed copy `dest` of `src` is identical, including its permissions,
+/// or `None` if there is no readable copy to compare
+async fn deployed_copy(src: &Path, dest: &Path) -> Option<bool> {
+    let deployed = fs::metadata(dest).await.ok()?;
+    let source = match fs::metadata(src).await {
+        Ok(m) => m,
+        Err(_) => return Some(false), // Copying reports the error
     };
+    if deployed.len() != source.len() || deployed.permissions() != source.permissions() {
+        return Some(false);
+    }
 
-    set_socket_path_override(browser, &mut ini);
-    ini.write_to_file(&path)
-        .with_context(|| path.display().to_string())
-        .with_context(|| format!("Unable to update Flatpak overrides for {browser}"))?;
+    let (Ok(deployed), Ok(source)) = tokio::join!(fs::read(dest), fs::read(src)) else {
+        return Some(false);
+    };
+    Some(deployed == source)
+}
+
+/// Delay before the first retry of a failed write, doubled for each further one
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Runs the write `op` on `path`, retrying it up to `retries` times if it fails with an
+/// error that is likely transient. Errors such as a denied permission fail right away.
+async fn retry_transient<T, F: Future<Output = std::io::Result<T>>>(
+    retries: u32,
+    path: &Path,
+    mut op: impl FnMut() -> F,
+) -> std::io::Result<T> {
+    let mut delay = WRITE_RETRY_DELAY;
+    for attempt in 1.. {
+        match op().await {
+            Err(e) if attempt <= retries && is_transient(&e) => {
+                debug!(
+                    "retrying write to {} ({attempt}/{retries}) in {delay:?}: {e}",
+                    path.display()
+                );
+                time::sleep(delay).await;
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    unreachable!()
+}
+
+/// Whether an IO error may go away by itself, e.g. on a busy or networked file system
+fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(
+            libc::EAGAIN
+                | libc::EBUSY
+                | libc::EINTR
+                | libc::ETXTBSY
+                | libc::ESTALE
+                | libc::ETIMEDOUT
+        )
+    )
+}
 
+/// Removes a previously deployed file, such that it is replaced instead of overwritten
+async fn remove_deployed(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e)
+            .with_context(|| path.display().to_string())
+            .context("Unable to remove deployed file"),
+        _ => Ok(()),
+    }
+}
+
+/// Warns about, or refuses with `strict`, files that other users than the current one and
+/// root can modify, as they could make the daemon run any native binary
+async fn check_permissions(path: &Path, strict: bool) -> Result<()> {
+    let metadata = fs::metadata(path).await.path_context(path)?;
+    let problem = match (metadata.uid(), metadata.mode()) {
+        (uid, _) if uid != getuid().as_raw() && uid != 0 => format!("owned by UID {uid}"),
+        (_, mode) if mode & 0o002 != 0 => "writable by all users".into(),
+        (_, mode) if mode & 0o020 != 0 => "writable by its group".into(),
+        _ => return Ok(()),
+    };
+
+    match strict {
+        true => bail!("{} is {problem}, refusing to use it", path.display()),
+        false => warn!(
+            "{} is {problem}, other users may be able to change which native binaries are run",
+            path.display()
+        ),
+    }
     Ok(())
 }
 
-#[instrument(level = "trace", skip(path), fields(path = %path.as_ref().display()))]
-async fn read_manifest(path: impl AsRef<Path>) -> Result<Value> {
+#[instrument(level = "trace", skip_all, fields(path = %path.as_ref().display()))]
+async fn read_manifest(path: impl AsRef<Path>, config: &Config) -> Result<Value> {
+    check_permissions(path.as_ref(), config.strict_permissions()).await?;
+
     let mut contents = String::new();
     fs::File::open(path)
         .await?
         .read_to_string(&mut contents)
         .await?;
 
-    Ok(serde_json::from_str(&contents)?)
+    manifest::parse_manifest(&contents, config.allow_manifest_comments())
 }
 
-#[instrument(skip_all, fields(browser = _browser, path = %entry.path().display()))]
-async fn install_manifest(entry: &DirEntry, _browser: &str, nmh_dir: &Path) -> Result<String> {
+#[instrument(skip_all, fields(browser = browser, path = %path.display()))]
+async fn install_manifest(
+    path: &Path,
+    file_name: &str,
+    browser: &str,
+    nmh_dir: &Path,
+    config: &Config,
+    force: bool,
+) -> Result<Option<(String, String)>> {
     // Read the manifest
-    let path = entry.path();
-    let mut manifest = read_manifest(&path)
+    let manifest = read_manifest(path, config)
         .await
         .with_context(|| path.display().to_string())
         .context("Unable to read app manifest")?;
 
-    // Extract the "path" field
-    let path = match &manifest["path"] {
-        Value::String(s) => s.into(),
-        _ => bail!("Malformed app manifest, \"path\" key missing"),
-    };
+    let original = manifest["path"].as_str().map(str::to_owned);
+    let mut proxied = manifest::proxy_manifest(
+        manifest,
+        file_name,
+        |n| config.binary_override(n).cloned(),
+        &config.manifest_client_path(Some(browser), nmh_dir),
+    )?;
+    for warning in &proxied.warnings {
+        warn!("{warning}");
+    }
+    if let Some(original) = original.filter(|o| *o != proxied.binary) {
+        info!(
+            "overriding native binary {} with {}",
+            original, proxied.binary
+        );
+    }
 
-    // Check that the "type" field is "stdio" (other formats are currently unsupported)
-    match &manifest["type"] {
-        Value::String(s) if s == "stdio" => (),
-        _ => bail!("Unsupported app manifest, only type \"stdio\" is currently supported"),
+    let binary = config.check_binary(&proxied.binary)?;
+    if binary != proxied.binary {
+        info!(
+            "resolved native binary symlink {} to {binary}",
+            proxied.binary
+        );
+        proxied.binary = binary;
     }
 
-    // Replace the path with the proxy client path
-    manifest["path"] = nmh_dir.join(PROXY_CLIENT_BIN).into_string_result()?.into();
+    // Adapt the app manifest to the browser, the managed keys can't be overridden
+    manifest::override_values(&mut proxied.manifest, config.manifest_overrides(browser))
+        .context("Unable to apply manifest_overrides")?;
 
     // Write the modified app manifest into the NMH directory
-    let deployment_path = nmh_dir.join(entry.file_name());
-    fs::write(&deployment_path, serde_json::to_vec_pretty(&manifest)?)
-        .await
-        .with_context(|| deployment_path.display().to_string())
-        .context("Failed to deploy app manifest")?;
-    Ok(path)
+    let deployment_path = nmh_dir.join(&proxied.file_name);
+    if force {
+        remove_deployed(&deployment_path).await?;
+    } else if let Some(path) = unmanaged_manifest(&deployment_path, browser, config).await {
+        warn!(
+            "{} registers native binary {path} without nm-proxy, not replacing it unless --force is given",
+            deployment_path.display()
+        );
+        return Ok(None);
+    }
+
+    let contents = config.manifest_style().serialize(&proxied.manifest)?;
+    retry_transient(config.write_retries(), &deployment_path, || {
+        fs::write(&deployment_path, &contents)
+    })
+    .await
+    .with_context(|| deployment_path.display().to_string())
+    .context("Failed to deploy app manifest")?;
+    Ok(Some((proxied.file_name, proxied.binary)))
 }
 
-#[instrument(level = "trace", skip_all)]
-async fn install_manifests(config: &Config, path: impl AsRef<Path>) -> Result<NativeBinaryMap> {
-    let manifest_dir = path.as_ref().join(APP_MANIFEST_DIR);
+/// Returns the "path" of the app manifest at `path` if it exists and doesn't point at a
+/// proxy client, i.e. it was installed by the user for running a native binary directly.
+/// Broken manifests can be replaced, as they don't work either way.
+async fn unmanaged_manifest(path: &Path, browser: &str, config: &Config) -> Option<String> {
+    let contents = fs::read_to_string(path).await.ok()?;
+    let manifest = manifest::parse_manifest(&contents, false).ok()?;
+    let binary = manifest["path"].as_str()?.to_owned();
+
+    let binary_path = Path::new(&binary);
+    let managed = binary_path.file_name() == Some(PROXY_CLIENT_BIN.as_ref())
+        || binary_path == config.proxy_client_path(Some(browser));
+    (!managed).then_some(binary)
+}
+
+/// Lists the app manifests (`.json` files) of a directory as (file name, path) pairs
+async fn list_manifests(mut stream: ReadDir, dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut manifests = Vec::new();
+    while let Some(entry) = stream.next_entry().await.path_context(dir)? {
+        let metadata = entry.metadata().await.path_context(entry.path())?;
+        let file_name = entry.file_name().into_string_result()?;
+        if metadata.is_file() && file_name.ends_with(".json") {
+            manifests.push((file_name, entry.path()));
+        }
+    }
+
+    Ok(manifests)
+}
 
+/// Resolves the manifests of a single source directory into `sources`, overriding entries
+/// with the same file name that were collected from earlier source directories
+#[instrument(level = "trace", skip(config, sources), fields(dir = %manifest_dir.display()))]
+async fn collect_manifests<'a>(
+    config: &'a Config,
+    manifest_dir: &Path,
+    sources: &mut HashMap<&'a String, BTreeMap<String, PathBuf>>,
+) -> Result<()> {
     // Open the manifest directory as a stream
-    let mut stream = match fs::read_dir(&manifest_dir).await {
+    let stream = match fs::read_dir(manifest_dir).await {
         Ok(s) => s,
         result @ Err(_) => {
             let kind = result.as_ref().err().map(|e| e.kind());
-            let result = result.path_context(&manifest_dir);
+            let result = result.path_context(manifest_dir);
             match kind {
-                Some(ErrorKind::NotFound) => result.manifest_help_context(&manifest_dir),
+                Some(ErrorKind::NotFound) => result.manifest_help_context(manifest_dir),
                 _ => result,
             }?
         }
     };
 
-    let mut native_binary_map = NativeBinaryMap::new();
-
-    // Install common manifests
-    while let Some(entry) = stream.next_entry().await.path_context(&manifest_dir)? {
-        let metadata = entry.metadata().await.path_context(entry.path())?;
-        let file_name = entry.file_name().into_string_result()?;
-        if !metadata.is_file() || !file_name.ends_with(".json") {
-            continue; // Skip all entries that are not app manifests
-        }
-
-        for (browser, nmh_dir) in config.nmh_dirs()? {
-            let mut browser_manifest = manifest_dir.join(browser);
-            browser_manifest.push(&file_name);
-            match fs::metadata(&browser_manifest).await {
-                // If an equivalent browser-specific manifest exists, it has precedence
-                Ok(m) if m.is_file() => continue,
-                Ok(m) => bail!("Expected file, found {:?}", m.file_type()),
-                Err(e) if e.kind() == ErrorKind::NotFound => (),
-                result @ Err(_) => result.path_context(&browser_manifest).map(|_| ())?,
-            }
-
-            // Install the manifest
-            let nmh_path = install_manifest(&entry, browser, &nmh_dir).await?;
-
-            // Track native binary paths per browser for host-side execution
-            native_binary_map
-                .entry(browser.into())
-                .or_insert(Default::default())
-                .insert(file_name.clone(), nmh_path);
+    // Collect common manifests
+    for (file_name, path) in list_manifests(stream, manifest_dir).await? {
+        for browser in config.browsers() {
+            sources
+                .entry(browser)
+                .or_default()
+                .insert(file_name.clone(), path.clone());
         }
     }
 
-    // Install browser-specific manifests
-    for (browser, nmh_dir) in config.nmh_dirs()? {
+    // Collect browser-specific manifests, these have precedence over the common ones
+    for browser in config.browsers() {
         let br_manifest_dir = manifest_dir.join(browser);
-        let mut stream = match fs::read_dir(&br_manifest_dir).await {
+        let stream = match fs::read_dir(&br_manifest_dir).await {
             Ok(s) => s,
             Err(e) if e.kind() == ErrorKind::NotFound => continue, // Skip if not found
             result @ Err(_) => result.path_context(&br_manifest_dir)?,
         };
 
-        while let Some(entry) = stream.next_entry().await.path_context(&br_manifest_dir)? {
-            let metadata = entry.metadata().await.path_context(entry.path())?;
-            let file_name = entry.file_name().into_string_result()?;
-            if !metadata.is_file() || !file_name.ends_with(".json") {
-                continue; // Skip all entries that are not app manifests
-            }
+        for (file_name, path) in list_manifests(stream, &br_manifest_dir).await? {
+            sources.entry(browser).or_default().insert(file_name, path);
+        }
+    }
+
+    Ok(())
+}
+
+#[instrument(level = "trace", skip_all)]
+async fn install_manifests(
+    config: &Config,
+    path: impl AsRef<Path>,
+    force: bool,
+    previous: Option<&DeploymentIndex>,
+    index: &mut DeploymentIndex,
+    failed: &mut Vec<(String, Error)>,
+) -> Result<NativeBinaryMap> {
+    // Later manifest directories override earlier ones by file name
+    let mut sources = HashMap::new();
+    for manifest_dir in config.manifest_dirs(path) {
+        collect_manifests(config, &manifest_dir, &mut sources).await?;
+    }
 
+    // Browsers sharing an NMH directory get each source manifest deployed there only once
+    let mut deployed = HashMap::<(PathBuf, PathBuf), Option<(String, String)>>::new();
+    let mut targets = HashMap::new();
+    let mut native_binary_map = NativeBinaryMap::new();
+    for (browser, nmh_dir) in config.nmh_dirs()? {
+        for (file_name, source) in sources.remove(browser).unwrap_or_default() {
             // Install the manifest
-            let nmh_path = install_manifest(&entry, browser, &nmh_dir).await?;
+            let result = match deployed.entry((nmh_dir.clone(), source)) {
+                Entry::Occupied(e) => e.get().clone(),
+                Entry::Vacant(e) => {
+                    let source = &e.key().1;
+                    let index = (previous, &mut *index);
+                    let result =
+                        deploy_source(source, &file_name, browser, &nmh_dir, config, force, index)
+                            .await;
+
+                    // A failing manifest fails its browser, but not the others
+                    let result = match result {
+                        Ok(r) => r,
+                        Err(err) => {
+                            let err = err.context(format!("Setup failed for browser {browser}"));
+                            failed.push((browser.clone(), err));
+                            native_binary_map.remove(browser);
+                            break;
+                        }
+                    };
+
+                    if let Some(result) = &result {
+                        // Differing browser-specific manifests can't coexist in a shared directory
+                        let target = nmh_dir.join(&result.0);
+                        if let Some(previous) = targets.insert(target.clone(), source.clone()) {
+                            warn!(
+                                "{} from {} replaced the one deployed from {}",
+                                target.display(),
+                                source.display(),
+                                previous.display()
+                            );
+                        }
+                    }
+
+                    e.insert(result).clone()
+                }
+            };
+            let Some((file_name, nmh_path)) = result else {
+                continue; // Left to the user-installed manifest
+            };
 
             // Track native binary paths per browser for host-side execution
             native_binary_map
                 .entry(browser.into())
-                .or_insert(Default::default())
+                .or_default()
                 .insert(file_name, nmh_path);
         }
     }
@@ -207,70 +432,470 @@ async fn install_manifests(config: &Config, path: impl AsRef<Path>) -> Result<Na
     Ok(native_binary_map)
 }
 
-#[instrument(level = "trace", skip(config))]
-fn set_socket_path_override(browser: &str, config: &mut Ini) {
-    let filesystems = config
-        .section(Some("Context"))
-        .map(|s| s.get("filesystems"))
-        .flatten()
-        .unwrap_or("")
-        .to_owned();
-
-    let socket_path = format!("xdg-run/{SOCKET_PREFIX}{browser}{SOCKET_SUFFIX}");
-    if filesystems.split(";").any(|e| e == socket_path) {
-        return; // Already configured
-    }
-
-    config.with_section(Some("Context")).set(
-        "filesystems",
-        match &*filesystems {
-            "" => socket_path,
-            s => format!("{s};{socket_path}"),
+/// Deploys a source manifest into `nmh_dir`, unless it is unchanged since the previous
+/// incremental run, returning the deployed file name and native binary
+async fn deploy_source(
+    source: &Path,
+    file_name: &str,
+    browser: &str,
+    nmh_dir: &Path,
+    config: &Config,
+    force: bool,
+    (previous, index): (Option<&DeploymentIndex>, &mut DeploymentIndex),
+) -> Result<Option<(String, String)>> {
+    let source_hash = fs::read(source)
+        .await
+        .map(|c| index::hash(&c))
+        .with_context(|| source.display().to_string())
+        .context("Unable to read app manifest")?;
+
+    // In incremental mode, manifests are only redeployed if they changed
+    let unchanged = match previous {
+        Some(p) => p.unchanged(nmh_dir, source, source_hash).await,
+        None => None,
+    };
+    let unchanged = match unchanged {
+        Some(entry) => recheck_unchanged(source, nmh_dir, entry, config).await?,
+        None => None,
+    };
+    let result = match unchanged {
+        Some(entry) => {
+            debug!("{} unchanged, not redeploying", source.display());
+            (entry.file_name.clone(), entry.binary.clone())
+        }
+        None => match install_manifest(source, file_name, browser, nmh_dir, config, force).await? {
+            Some(result) => result,
+            None => return Ok(None),
         },
-    );
+    };
+
+    index.record(nmh_dir, source, source_hash, &result).await?;
+    Ok(Some(result))
 }
 
-#[tokio::main]
-#[instrument]
-async fn main() -> Result<()> {
-    // Initialize the logging framework
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env_lossy(),
-        )
-        .with_span_events(FmtSpan::NEW)
-        .init();
+/// Repeats the checks of deploying for a source manifest that is unchanged since its
+/// deployment, as permissions and native binary symlinks may have changed. Returns `None`
+/// if the native binary now resolves differently, for the manifest to be redeployed.
+async fn recheck_unchanged<'a>(
+    source: &Path,
+    nmh_dir: &Path,
+    entry: &'a IndexEntry,
+    config: &Config,
+) -> Result<Option<&'a IndexEntry>> {
+    let strict = config.strict_permissions();
+    check_permissions(source, strict)
+        .await
+        .with_context(|| source.display().to_string())
+        .context("Unable to read app manifest")?;
+    check_permissions(&nmh_dir.join(&entry.file_name), strict).await?;
 
-    // Acquire the runtime directory path
-    let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None)?;
+    let binary = config.check_binary(&entry.binary)?;
+    Ok((binary == entry.binary).then_some(entry))
+}
+
+/// Acquires an advisory lock that prevents concurrent setup runs from racing on the deployment
+#[instrument(level = "trace", skip(runtime_dir))]
+fn lock_setup(runtime_dir: impl AsRef<Path>) -> Result<Flock<StdFile>> {
+    let path = runtime_dir.as_ref().join(SETUP_LOCK_FILE_NAME);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|e| runtime::full_dir_context(e, &runtime_dir))
+        .with_context(|| path.display().to_string())
+        .context("Unable to open setup lock file")?;
+
+    Flock::lock(file, FlockArg::LockExclusiveNonblock).map_err(|(_, e)| match e {
+        Errno::EWOULDBLOCK => anyhow!("Another setup is already in progress, try again later"),
+        e => Error::from(e)
+            .context(path.display().to_string())
+            .context("Unable to lock setup lock file"),
+    })
+}
+
+/// Validates a single app manifest like deployment would and prints the result, without deploying
+async fn check_manifest(config: &Config, path: &Path) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("{} is not a file", path.display()))?
+        .to_os_string()
+        .into_string_result()?;
+    let manifest = read_manifest(path, config)
+        .await
+        .with_context(|| path.display().to_string())
+        .context("Unable to read app manifest")?;
+
+    let proxied = manifest::proxy_manifest(
+        manifest,
+        &file_name,
+        |n| config.binary_override(n).cloned(),
+        &config.manifest_client_path(None, "<nmh_dir>"),
+    )
+    .with_context(|| path.display().to_string())?;
+
+    // The daemon launches the native binary as-is, so it must be runnable from anywhere
+    let mut problems = proxied.warnings;
+    let binary = Path::new(&proxied.binary);
+    if !binary.is_absolute() {
+        problems.push(format!(
+            "native binary {} is not an absolute path",
+            binary.display()
+        ));
+    } else {
+        match binary.metadata() {
+            Ok(m) if m.is_file() && m.permissions().mode() & 0o111 != 0 => (),
+            Ok(_) => problems.push(format!(
+                "native binary {} is not executable",
+                binary.display()
+            )),
+            Err(e) => problems.push(format!("native binary {}: {e}", binary.display())),
+        }
+    }
+
+    println!("registered as: {}", proxied.file_name);
+    println!("native binary: {}", proxied.binary);
+    let contents = config.manifest_style().serialize(&proxied.manifest)?;
+    println!("{}", String::from_utf8_lossy(&contents));
+    for problem in &problems {
+        println!("problem: {problem}");
+    }
+
+    if !problems.is_empty() {
+        bail!("{} has {} problem(s)", path.display(), problems.len());
+    }
+
+    Ok(())
+}
+
+/// Resolves everything that setup derives from the configuration, without touching the
+/// filesystem. Socket paths are only checked if the runtime directory is known.
+fn validate_config(config: &Config, config_path: &Path) -> Result<()> {
+    config.nmh_base_dir()?;
+    #[cfg(target_os = "linux")]
+    let _ = config.override_paths()?; // Expands the app IDs
+    config.daemon_settings();
+
+    let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None).ok();
+    if runtime_dir.is_none() {
+        info!("XDG_RUNTIME_DIR is not set, skipping the socket path checks");
+    }
+
+    let mut browsers = 0;
+    for (browser, _) in config.nmh_dirs()? {
+        if let Some(dir) = &runtime_dir {
+            runtime::check_socket_path(dir, browser)
+                .with_context(|| format!("Invalid configuration for browser {browser}"))?;
+        }
+        browsers += 1;
+    }
+
+    let dirs = config.manifest_dirs(config_path);
+    if !dirs.iter().any(|d| d.is_dir()) {
+        warn!("none of the manifest directories exist, there is nothing to deploy");
+    }
+
+    info!("configuration is valid, {browsers} browser(s) enabled");
+    Ok(())
+}
+
+/// Prints the configuration with all paths resolved, as setup and the daemon would use them
+fn print_config(config: &Config, config_path: &Path, layers: &[(PathBuf, String)]) -> Result<()> {
+    println!("configuration directory: {}", config_path.display());
+    for (path, _) in layers {
+        println!("configuration file: {}", path.display());
+    }
+    println!("proxy client: {}", config.proxy_client_path(None).display());
+    println!("client deployment: {:?}", config.client_deployment());
+    println!("nmh base directory: {}", config.nmh_base_dir()?.display());
+    for dir in config.manifest_dirs(config_path) {
+        println!("manifest directory: {}", dir.display());
+    }
+
+    // The runtime directory is only needed for showing the full socket paths
+    let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None).ok();
+
+    #[cfg(target_os = "linux")]
+    let override_paths: HashMap<_, _> = config.override_paths()?.collect();
+    let mut nmh_dirs: Vec<_> = config.nmh_dirs()?.collect();
+    nmh_dirs.sort();
+    for (browser, nmh_dir) in nmh_dirs {
+        println!("\n[browsers.{browser}]");
+        println!("nmh_dir: {}", nmh_dir.display());
+        println!(
+            "manifest proxy client: {}",
+            config
+                .manifest_client_path(Some(browser), &nmh_dir)
+                .display()
+        );
+        #[cfg(target_os = "linux")]
+        if let Some(path) = override_paths.get(browser) {
+            println!("flatpak overrides: {}", path.display());
+        }
+
+        let socket = common::socket_file_name(browser);
+        match &runtime_dir {
+            Some(dir) => println!("socket: {}", Path::new(dir).join(socket).display()),
+            None => println!("socket: $XDG_RUNTIME_DIR/{socket}"),
+        }
+    }
+
+    println!(
+        "\n# Runtime settings passed to the daemon\n{}",
+        toml::to_string_pretty(&config.daemon_settings())
+            .context("Failed to serialize runtime settings")?
+    );
+    Ok(())
+}
+
+/// Performs the deployment, recording what was done into `report`
+async fn setup(args: &args::Args, filter: Option<&LogFilter>, report: &mut Report) -> Result<()> {
+    if args.init {
+        #[cfg(target_os = "linux")]
+        return init::init_config(args.force).await;
+        #[cfg(not(target_os = "linux"))]
+        bail!("--init detects Flatpak browsers, which are only supported on Linux");
+    }
 
     // Load configuration
     let config_path = config::form_config_path().await?;
-    let config = config::load_config(&config_path).await?;
+    let layers = config::read_config_layers(&config_path).await?;
+    let mut config = config::load_config(&layers)?;
+    if let Some(filter) = filter {
+        filter.configure(config.log_level());
+    }
+    for (path, _) in &layers {
+        check_permissions(path, config.strict_permissions()).await?;
+    }
     debug!("configuration: {:?}", config);
 
+    // Configurations shared between machines may list browsers that aren't installed here
+    for (browser, path) in config.disable_unavailable_browsers() {
+        info!(
+            "skipping browser {browser}, its require_path {} does not exist",
+            path.display()
+        );
+    }
+
+    if args.print_config {
+        return print_config(&config, &config_path, &layers);
+    }
+
+    if args.validate_config {
+        return validate_config(&config, &config_path);
+    }
+
+    if let Some(path) = &args.check_manifest {
+        return check_manifest(&config, path).await;
+    }
+
+    if args.diagnose {
+        #[cfg(target_os = "linux")]
+        return flatpak::diagnose(&config).await;
+        #[cfg(not(target_os = "linux"))]
+        bail!("--diagnose checks Flatpak overrides, which are only supported on Linux");
+    }
+
+    // Acquire the runtime directory path
+    let runtime_dir = common::parse_env("XDG_RUNTIME_DIR", None)?;
+    runtime::check_runtime_dir(&runtime_dir)?;
+
+    // Prevent concurrent runs, the lock is released on exit
+    let _lock = lock_setup(&runtime_dir)?;
+
+    for (nmh_dir, browsers) in config.shared_nmh_dirs()? {
+        let names = browsers.iter().map(|b| b.as_str()).collect::<Vec<_>>();
+        warn!(
+            "{} share the NMH directory {}, deploying it once for all of them",
+            names.join(", "),
+            nmh_dir.display()
+        );
+
+        // Only one proxy client can be deployed there
+        let clients: HashSet<_> = names
+            .iter()
+            .map(|b| config.proxy_client_path(Some(b)))
+            .collect();
+        if clients.len() > 1 {
+            warn!(
+                "{} have differing proxy clients, only one of them is deployed",
+                names.join(", ")
+            );
+        }
+    }
+
+    // Failures of individual browsers are reported at the end, after setting up the others
+    let mut failures = Vec::new();
+    let mut failed = Vec::new();
+
+    let mut created = HashMap::new();
     for (browser, nmh_dir) in config.nmh_dirs()? {
-        // Create native messaging host directory
-        create_nmh_dir(browser, &nmh_dir).await?;
+        if let Err(e) = runtime::check_socket_path(&runtime_dir, browser) {
+            failures.push(e.context(format!("Setup failed for browser {browser}")));
+            failed.push(browser.clone());
+            continue;
+        }
+
+        let deployed = match created.entry(nmh_dir.clone()) {
+            Entry::Occupied(e) => *e.get(),
+            Entry::Vacant(e) => {
+                let result = async {
+                    // Create native messaging host directory
+                    create_nmh_dir(browser, &nmh_dir, config.require_parent_dirs()).await?;
+
+                    // Install proxy client, unless all manifests point at a shared one
+                    if config.client_deployment() == ClientDeployment::Copy {
+                        install_proxy_client(browser, &nmh_dir, &config, args.force).await?;
+                    }
+                    anyhow::Ok(())
+                }
+                .await;
+
+                let deployed = match result {
+                    Ok(()) => true,
+                    Err(err) => {
+                        failures.push(err.context(format!("Setup failed for browser {browser}")));
+                        failed.push(browser.clone());
+                        false
+                    }
+                };
+                *e.insert(deployed)
+            }
+        };
+
+        // Browsers sharing the directory fail with it
+        if !deployed {
+            if !failed.contains(browser) {
+                warn!("skipping browser {browser}, its shared NMH directory could not be set up");
+                failed.push(browser.clone());
+            }
+            continue;
+        }
+
+        report.browsers.insert(
+            browser.clone(),
+            BrowserReport {
+                proxy_client: config.manifest_client_path(Some(browser), &nmh_dir),
+                nmh_dir,
+                ..Default::default()
+            },
+        );
+    }
 
-        // Install proxy client
-        install_proxy_client(browser, &nmh_dir, &config).await?;
+    for browser in failed {
+        config.disable_browser(&browser);
     }
 
-    // Configure Flatpak overrides
-    for (browser, path) in config.override_paths()? {
-        configure_flatpak_overrides(browser, &path).await?;
+    // Only the index of a run with the same configuration can tell what is unchanged
+    let config_contents = layers
+        .iter()
+        .map(|(_, c)| c.as_str())
+        .collect::<Vec<_>>()
+        .join("\0");
+    let state_dir = config::state_dir()?;
+    let previous = match args.incremental && !args.force {
+        true => DeploymentIndex::load(&state_dir, config_contents.as_bytes()).await,
+        false => None,
+    };
+    if args.incremental && previous.is_none() {
+        info!("no usable deployment index, deploying all app manifests");
     }
 
     // Install manifests
-    let native_binaries = install_manifests(&config, &config_path).await?;
+    let mut index = DeploymentIndex::new(config_contents.as_bytes());
+    let mut failed = Vec::new();
+    let native_binaries = install_manifests(
+        &config,
+        &config_path,
+        args.force,
+        previous.as_ref(),
+        &mut index,
+        &mut failed,
+    )
+    .await?;
+    for (browser, err) in failed {
+        report.browsers.remove(&browser);
+        config.disable_browser(&browser);
+        failures.push(err);
+    }
+    index.save(&state_dir).await?;
+
+    // Configure Flatpak overrides, only for the browsers that were set up, so that failed
+    // ones aren't granted access to a socket nothing is deployed for
+    #[cfg(target_os = "linux")]
+    {
+        report.overrides = flatpak::configure_overrides(&config, &mut failures).await?;
+    }
     debug!("native binary map: {:?}", native_binaries);
+    for (browser, manifests) in &native_binaries {
+        if let Some(b) = report.browsers.get_mut(browser) {
+            b.manifests = manifests
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+        }
+    }
 
     // Save runtime configuration
-    Settings { native_binaries }.save(runtime_dir).await?;
+    Settings {
+        native_binaries,
+        daemon: config.daemon_settings(),
+    }
+    .save(runtime_dir)
+    .await?;
+
+    if !failures.is_empty() {
+        let list = failures
+            .iter()
+            .map(|e| format!("\n  {e:#}"))
+            .collect::<String>();
+        bail!(
+            "Setup failed with {} error(s), the remaining browsers were set up:{list}",
+            failures.len()
+        );
+    }
 
     info!("setup complete");
     Ok(())
 }
+
+#[tokio::main]
+#[instrument]
+async fn main() -> Result<()> {
+    // Parse command line arguments
+    let args = args::parse_args()?;
+
+    // Initialize the logging framework, in JSON mode only warnings are collected for the report
+    let warnings = WarningCollector::default();
+    let filter = if args.json {
+        tracing_subscriber::registry().with(warnings.clone()).init();
+        None
+    } else {
+        let (layer, filter) = LogFilter::new(LevelFilter::INFO);
+        tracing_subscriber::registry()
+            .with(layer)
+            .with(tracing_subscriber::fmt::layer().with_span_events(FmtSpan::NEW))
+            .init();
+        Some(filter)
+    };
+
+    let mut report = Report::default();
+    let result = setup(&args, filter.as_ref(), &mut report).await;
+    if !args.json {
+        return result;
+    }
+
+    report.success = result.is_ok();
+    report.error = result.err().map(|e| format!("{e:#}"));
+    report.warnings = warnings.take();
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).context("Failed to serialize setup report")?
+    );
+
+    if !report.success {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+/// Deployment of a single browser
+#[derive(Serialize, Debug, Default)]
+pub struct BrowserReport {
+    pub nmh_dir: PathBuf,
+    pub proxy_client: PathBuf,
+    /// Native binaries by deployed app manifest file name
+    pub manifests: BTreeMap<String, String>,
+}
+
+/// Result of a setup run, printed as JSON for machine consumption with `--json`
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub browsers: BTreeMap<String, BrowserReport>,
+    /// Flatpak override files that were updated
+    pub overrides: Vec<PathBuf>,
+    pub warnings: Vec<String>,
+}
+
+/// Collects the messages of warning and error events for the report
+#[derive(Clone, Default)]
+pub struct WarningCollector(Arc<Mutex<Vec<String>>>);
+
+impl WarningCollector {
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl<S: Subscriber> Layer<S> for WarningCollector {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() > Level::WARN {
+            return; // Less severe than a warning
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.0.lock().unwrap().push(visitor.0);
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
@@ -0,0 +1,392 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use nm_proxy::common::config;
+use nm_proxy::common::config::{ClientDeployment, Config};
+use nm_proxy::common::constants::*;
+use nm_proxy::common::runtime::LogLevel;
+
+const FIREFOX: &str = r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+"#;
+
+/// Parses configuration layers in increasing order of precedence, the first one continuing
+/// a `[daemon]` table with a proxy client
+fn parse(layers: &[&str]) -> Config {
+    let layers = layers
+        .iter()
+        .enumerate()
+        .map(|(i, contents)| {
+            let contents = match i {
+                0 => format!("[daemon]\nproxy_client = \"/opt/nm-proxy/client\"\n{contents}"),
+                _ => contents.to_string(),
+            };
+            (format!("/layer-{i}/config.toml").into(), contents)
+        })
+        .collect::<Vec<_>>();
+    config::parse_config_layers(&layers).unwrap()
+}
+
+#[test]
+fn client_deployment_copy() {
+    let config = parse(&[FIREFOX]);
+    let nmh_dir = Path::new("/nmh");
+
+    assert_eq!(config.client_deployment(), ClientDeployment::Copy);
+    assert_eq!(
+        config.manifest_client_path(None, nmh_dir),
+        nmh_dir.join(PROXY_CLIENT_BIN)
+    );
+}
+
+#[test]
+fn client_deployment_shared() {
+    let config = parse(&[&format!("client_deployment = \"shared\"\n{FIREFOX}")]);
+
+    assert_eq!(config.client_deployment(), ClientDeployment::Shared);
+    assert_eq!(
+        config.manifest_client_path(None, "/nmh"),
+        Path::new("/opt/nm-proxy/client")
+    );
+}
+
+#[test]
+fn disabled_browser_skipped() {
+    let config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+
+[browsers.chromium]
+enabled = false
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+"#]);
+
+    assert_eq!(config.browsers().collect::<Vec<_>>(), ["firefox"]);
+    assert_eq!(config.nmh_dirs().unwrap().count(), 1);
+    #[cfg(target_os = "linux")]
+    assert_eq!(config.override_paths().unwrap().count(), 1);
+}
+
+#[test]
+fn shared_nmh_dir_detected() {
+    let config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+
+[browsers.firefox-dev]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+"#]);
+
+    let shared = config.shared_nmh_dirs().unwrap();
+    assert_eq!(shared.len(), 1);
+    assert!(shared[0].0.ends_with(".mozilla/native-messaging-hosts"));
+    assert_eq!(shared[0].1, ["firefox", "firefox-dev"]);
+}
+
+#[test]
+fn nmh_dir_template_expanded() {
+    let config = parse(&[r#"
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/{browser}/NativeMessagingHosts"
+"#]);
+
+    let (browser, nmh_dir) = config.nmh_dirs().unwrap().next().unwrap();
+    assert_eq!(browser, "chromium");
+    assert!(nmh_dir.ends_with(".config/chromium/NativeMessagingHosts"));
+}
+
+#[test]
+fn unknown_template_variable() {
+    let config = parse(&[r#"
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/{name}/NativeMessagingHosts"
+"#]);
+
+    let error = format!("{:#}", config.nmh_dirs().err().unwrap());
+    assert!(error.contains("nmh_dir of browser chromium"));
+    assert!(error.contains("Unknown template variable {name}"));
+}
+
+#[test]
+fn absolute_nmh_dir_rejected() {
+    let config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = "/home/user/.mozilla/native-messaging-hosts"
+"#]);
+
+    let error = config.nmh_dirs().err().unwrap().to_string();
+    assert!(error.contains("NMH directory of browser firefox must be relative"));
+}
+
+#[test]
+fn browser_without_required_path_disabled() {
+    let mut config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+require_path = "/"
+
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+require_path = "/nonexistent/nm-proxy"
+"#]);
+
+    let disabled = config.disable_unavailable_browsers();
+    assert_eq!(
+        disabled,
+        [("chromium".into(), "/nonexistent/nm-proxy".into())]
+    );
+    assert_eq!(config.browsers().collect::<Vec<_>>(), ["firefox"]);
+}
+
+#[test]
+fn flatpak_app_base_overridden() {
+    let config = parse(&[r#"
+[setup]
+flatpak_app_base = "/srv/flatpak/app"
+
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+"#]);
+
+    let (_, nmh_dir) = config.nmh_dirs().unwrap().next().unwrap();
+    #[cfg(not(target_os = "macos"))]
+    assert_eq!(
+        nmh_dir,
+        Path::new("/srv/flatpak/app/org.mozilla.firefox/.mozilla/native-messaging-hosts")
+    );
+    #[cfg(target_os = "macos")]
+    assert!(nmh_dir.ends_with("Library/Application Support/.mozilla/native-messaging-hosts"));
+}
+
+#[test]
+fn macos_nmh_dir_selected() {
+    let config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+macos_nmh_dir = "Mozilla/NativeMessagingHosts"
+"#]);
+
+    let (_, nmh_dir) = config.nmh_dirs().unwrap().next().unwrap();
+    #[cfg(not(target_os = "macos"))]
+    assert!(nmh_dir.ends_with("org.mozilla.firefox/.mozilla/native-messaging-hosts"));
+    #[cfg(target_os = "macos")]
+    assert!(nmh_dir.ends_with("Library/Application Support/Mozilla/NativeMessagingHosts"));
+}
+
+#[test]
+fn browser_proxy_client_overridden() {
+    let config = parse(&[r#"
+client_deployment = "shared"
+
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+proxy_client = "/opt/nm-proxy/client-static"
+
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+"#]);
+
+    assert_eq!(
+        config.manifest_client_path(Some("firefox"), "/nmh"),
+        Path::new("/opt/nm-proxy/client-static")
+    );
+    assert_eq!(
+        config.manifest_client_path(Some("chromium"), "/nmh"),
+        Path::new("/opt/nm-proxy/client")
+    );
+}
+
+#[test]
+fn socket_name_mapped() {
+    let config = parse(&[r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+socket_name = "mozilla"
+
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+"#]);
+
+    let settings = config.daemon_settings();
+    assert_eq!(settings.socket_name("firefox"), "mozilla");
+    assert_eq!(settings.socket_name("chromium"), "chromium");
+}
+
+#[test]
+fn empty_config_rejected() {
+    for contents in ["", " \n\t\n", "# [daemon]\n# proxy_client = \"client\"\n"] {
+        let layers = [("config.toml".into(), contents.into())];
+        let error = config::parse_config_layers(&layers).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the configuration file is empty"));
+    }
+}
+
+#[test]
+fn layered_browsers_merged() {
+    let config = parse(&[
+        r#"
+
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+
+[browsers.chromium]
+app_id = "org.chromium.Chromium"
+nmh_dir = ".config/chromium/NativeMessagingHosts"
+"#,
+        r#"
+[browsers.chromium]
+enabled = false
+
+[browsers.librewolf]
+app_id = "io.gitlab.librewolf-community"
+nmh_dir = ".librewolf/native-messaging-hosts"
+"#,
+    ]);
+
+    let mut browsers = config.browsers().collect::<Vec<_>>();
+    browsers.sort();
+    assert_eq!(browsers, ["firefox", "librewolf"]);
+}
+
+#[test]
+fn layered_daemon_settings_overridden() {
+    let config = parse(&[
+        r#"
+workers = 2
+compression = true
+
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+"#,
+        r#"
+[daemon]
+workers = 8
+"#,
+    ]);
+
+    let settings = config.daemon_settings();
+    assert_eq!(settings.workers, NonZeroUsize::new(8));
+    assert!(settings.compression);
+    assert_eq!(
+        config.proxy_client_path(None),
+        Path::new("/opt/nm-proxy/client")
+    );
+}
+
+#[test]
+fn log_level_configured() {
+    let config = parse(&[r#"
+[logging]
+level = "debug"
+
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/native-messaging-hosts"
+"#]);
+
+    assert_eq!(config.log_level(), Some(LogLevel::Debug));
+    assert_eq!(config.daemon_settings().log_level, Some(LogLevel::Debug));
+    assert_eq!(parse(&[FIREFOX]).log_level(), None);
+}
+
+#[test]
+fn path_variables_expanded() {
+    // Tests run in parallel, so the variable is injected rather than set in the environment
+    let expand = |path| {
+        config::expand_path_with(path, |name| match name {
+            "NM_PROXY_TEST_EXPAND" => Ok("nm-proxy".into()),
+            _ => Err(anyhow::anyhow!("{name} is not set")),
+        })
+    };
+    let home = std::env::var("HOME").unwrap();
+
+    assert_eq!(
+        expand("~/$NM_PROXY_TEST_EXPAND/${NM_PROXY_TEST_EXPAND}-client").unwrap(),
+        Path::new(&home).join("nm-proxy/nm-proxy-client")
+    );
+    assert_eq!(
+        expand("/opt/$$NM_PROXY_TEST_EXPAND").unwrap(),
+        Path::new("/opt/$NM_PROXY_TEST_EXPAND")
+    );
+}
+
+#[test]
+fn undefined_path_variable_rejected() {
+    for path in [
+        "~/$NM_PROXY_TEST_UNDEFINED/client",
+        "/opt/${NM_PROXY_TEST_EXPAND",
+    ] {
+        assert!(config::expand_path(path).is_err(), "{path}");
+    }
+
+    let error = format!(
+        "{:#}",
+        config::expand_path("$NM_PROXY_TEST_UNDEFINED").unwrap_err()
+    );
+    assert!(error.contains("NM_PROXY_TEST_UNDEFINED is not set"));
+}
+
+#[test]
+fn binary_symlinks_checked() {
+    let dir = std::env::temp_dir().join(format!("nm-proxy-test-{}-symlinks", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let binary = dir.join("binary");
+    let link = dir.join("link");
+    std::fs::write(&binary, "").unwrap();
+    let _ = std::fs::remove_file(&link);
+    std::os::unix::fs::symlink(&binary, &link).unwrap();
+    let (binary, link) = (binary.to_str().unwrap(), link.to_str().unwrap());
+    let real = std::fs::canonicalize(binary).unwrap();
+
+    let setup = |s: &str| {
+        parse(&[&format!(
+            "[setup]\n{s}\n[browsers.firefox]\napp_id = \"org.mozilla.firefox\"\nnmh_dir = \"x\""
+        )])
+    };
+    assert_eq!(setup("").check_binary(link).unwrap(), link);
+    assert_eq!(
+        setup(r#"binary_symlinks = "resolve""#)
+            .check_binary(link)
+            .unwrap(),
+        real.to_str().unwrap()
+    );
+
+    let reject = setup(r#"binary_symlinks = "reject""#);
+    assert_eq!(reject.check_binary(binary).unwrap(), binary);
+    assert!(reject.check_binary(link).is_err());
+    let allowed = format!(
+        "binary_symlinks = \"reject\"\nsymlink_targets = [{:?}]",
+        real.parent().unwrap()
+    );
+    assert_eq!(setup(&allowed).check_binary(link).unwrap(), link);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
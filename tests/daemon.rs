@@ -0,0 +1,130 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::os::fd::OwnedFd;
+use std::os::unix::net::UnixStream;
+
+use nm_proxy::common::constants::*;
+use nm_proxy::common::runtime::Settings;
+use nm_proxy::daemon;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+/// Creates a file descriptor standing in for a socket passed by systemd
+fn fake_fd() -> OwnedFd {
+    UnixStream::pair().unwrap().0.into()
+}
+
+fn settings(browsers: &[&str]) -> Settings {
+    Settings {
+        native_binaries: browsers
+            .iter()
+            .map(|b| {
+                (
+                    b.to_string(),
+                    HashMap::from([("a.json".into(), "/bin/cat".into())]),
+                )
+            })
+            .collect(),
+        daemon: Default::default(),
+    }
+}
+
+#[test]
+fn named_sockets() {
+    let sockets = daemon::named_sockets([
+        (Some("firefox".into()), fake_fd()),
+        (Some("chromium".into()), fake_fd()),
+    ])
+    .unwrap();
+
+    assert!(sockets.contains_key("firefox"));
+    assert!(sockets.contains_key("chromium"));
+}
+
+#[test]
+fn named_sockets_duplicate() {
+    let result = daemon::named_sockets([
+        (Some("firefox".into()), fake_fd()),
+        (Some("firefox".into()), fake_fd()),
+    ]);
+
+    assert!(format!("{:#}", result.unwrap_err()).contains("duplicate socket name"));
+}
+
+#[test]
+fn named_sockets_unnamed() {
+    assert!(daemon::named_sockets([(None, fake_fd())]).is_err());
+}
+
+#[tokio::test]
+async fn socket_not_found() {
+    let sockets = HashMap::from([("chromium".into(), fake_fd())]);
+    let result = daemon::run(sockets, settings(&["firefox"]), CancellationToken::new()).await;
+
+    assert!(format!("{:#}", result.unwrap_err()).contains("firefox: socket not found"));
+}
+
+#[tokio::test]
+async fn no_native_binaries() {
+    let sockets = HashMap::from([("firefox".into(), fake_fd())]);
+    let result = daemon::run(sockets, settings(&[]), CancellationToken::new()).await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn test_spawn_echoed() {
+    let message = json!({"message": "hello"});
+    let spawned =
+        daemon::probe::test_spawn(&settings(&["firefox", "chromium"]), "a.json", &message)
+            .await
+            .unwrap();
+
+    assert_eq!(spawned.browser, "chromium");
+    assert_eq!(spawned.response, message);
+}
+
+#[tokio::test]
+async fn test_spawn_unregistered() {
+    let error = daemon::probe::test_spawn(&settings(&["firefox"]), "b.json", &json!({}))
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("b.json"));
+}
+
+#[tokio::test]
+async fn socket_name_mapped() {
+    let mapped = || {
+        let mut settings = settings(&["firefox"]);
+        settings.daemon.socket_names = HashMap::from([("firefox".into(), "ff".into())]);
+        settings
+    };
+
+    let token = CancellationToken::new();
+    token.cancel();
+    let sockets = HashMap::from([("ff".into(), fake_fd())]);
+    daemon::run(sockets, mapped(), token).await.unwrap();
+
+    let sockets = HashMap::from([("firefox".into(), fake_fd())]);
+    let result = daemon::run(sockets, mapped(), CancellationToken::new()).await;
+    assert!(format!("{:#}", result.unwrap_err()).contains("firefox: socket named ff not found"));
+}
+
+#[tokio::test]
+async fn corrupt_settings_reported() {
+    let dir = std::env::temp_dir().join(format!("nm-proxy-test-{}-corrupt", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut contents = toml::to_string(&settings(&["firefox"]))
+        .unwrap()
+        .into_bytes();
+    contents.extend(b"# \xff\n");
+    std::fs::write(dir.join(SETTINGS_FILE_NAME), contents).unwrap();
+
+    let error = Settings::load(&dir).await.unwrap_err().to_string();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(error.contains("invalid UTF-8"), "{error}");
+    assert!(error.contains("the rest of the file is intact"), "{error}");
+    assert!(error.contains("run setup again"), "{error}");
+}
@@ -0,0 +1,750 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use nix::sys::signal::kill;
+use nix::sys::socket::{self, AddressFamily, Backlog, SockFlag, SockType, UnixAddr};
+use nix::unistd::Pid;
+use nm_proxy::common;
+use nm_proxy::common::constants::*;
+use nm_proxy::common::keepalive::{MAX_LINK_FRAME_SIZE, SESSION_END, TERMINATE};
+use nm_proxy::common::runtime::{DaemonSettings, ManifestMode, ManifestSettings, Settings};
+use nm_proxy::common::{HandshakeMessage, HandshakeReply};
+use nm_proxy::daemon;
+use nm_proxy::daemon::capture::{self, Direction};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// Daemon serving a single browser socket, with `cat` as an echoing native binary by default
+struct TestDaemon {
+    path: PathBuf,
+    token: CancellationToken,
+    handle: JoinHandle<anyhow::Result<()>>,
+}
+
+impl TestDaemon {
+    fn start(name: &str, daemon: DaemonSettings) -> Self {
+        Self::start_with(name, "/bin/cat", daemon)
+    }
+
+    fn start_with(name: &str, binary: &str, daemon: DaemonSettings) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "nm-proxy-test-{}-{name}.socket",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        Self::serve(path, listener.into(), binary, daemon)
+    }
+
+    /// Serves the socket `fd` bound at `path`
+    fn serve(path: PathBuf, fd: OwnedFd, binary: &str, daemon: DaemonSettings) -> Self {
+        let settings = Settings {
+            native_binaries: HashMap::from([(
+                "firefox".into(),
+                HashMap::from([("a.json".into(), binary.into())]),
+            )]),
+            daemon,
+        };
+
+        let token = CancellationToken::new();
+        let sockets = HashMap::from([("firefox".into(), fd)]);
+        let handle = tokio::spawn(daemon::run(sockets, settings, token.clone()));
+        Self {
+            path,
+            token,
+            handle,
+        }
+    }
+
+    async fn connect(&self, compression: bool) -> (UnixStream, HandshakeReply) {
+        self.connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![], // Would be taken as files by cat
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await
+    }
+
+    async fn connect_with(&self, handshake: HandshakeMessage) -> (UnixStream, HandshakeReply) {
+        let mut stream = UnixStream::connect(&self.path).await.unwrap();
+        common::send_nm_object(&mut stream, &handshake)
+            .await
+            .unwrap();
+        let reply = common::recv_nm_object(&mut stream).await.unwrap();
+        (stream, reply)
+    }
+
+    async fn stop(self) -> anyhow::Result<()> {
+        self.token.cancel();
+        let result = self.handle.await.unwrap();
+        std::fs::remove_file(&self.path).unwrap();
+        result
+    }
+}
+
+#[tokio::test]
+async fn messages_echoed() {
+    let daemon = TestDaemon::start("echo", Default::default());
+    let (mut stream, reply) = daemon.connect(false).await;
+    assert_eq!(reply.error, None);
+
+    for object in [
+        json!({"message": "hello"}),
+        json!(["x".repeat(100_000), 42]),
+    ] {
+        common::send_nm_object(&mut stream, &object).await.unwrap();
+        assert_eq!(
+            common::recv_nm_object::<Value>(&mut stream).await.unwrap(),
+            object
+        );
+    }
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn compressed_messages_echoed() {
+    let settings = DaemonSettings {
+        compression: true,
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("compressed", settings);
+    let (mut stream, reply) = daemon.connect(true).await;
+    assert!(reply.compression);
+
+    let message = serde_json::to_vec(&json!({"message": "hello ".repeat(100)})).unwrap();
+    let body = lz4_flex::compress_prepend_size(&message);
+    stream
+        .write_all(&(body.len() as u32).to_ne_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&body).await.unwrap();
+
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).await.unwrap();
+    let mut echoed = vec![0; u32::from_ne_bytes(length) as usize];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(
+        lz4_flex::decompress_size_prepended(&echoed).unwrap(),
+        message
+    );
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn oversized_output_rejected() {
+    let manifest = ManifestSettings {
+        max_output_size: Some(100),
+        ..Default::default()
+    };
+    let settings = DaemonSettings {
+        manifests: HashMap::from([("a.json".into(), manifest)]),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("oversized", settings);
+    let (mut stream, _) = daemon.connect(false).await;
+
+    // The echo exceeds the cap, so the session ends without forwarding it
+    common::send_nm_object(&mut stream, "x".repeat(200))
+        .await
+        .unwrap();
+    assert!(common::recv_nm_object::<Value>(&mut stream).await.is_err());
+
+    drop(stream);
+    let error = format!("{:#}", daemon.stop().await.unwrap_err());
+    assert!(error.contains("exceeds maximum of 100 bytes"));
+}
+
+#[tokio::test]
+async fn session_captured() {
+    let dir = std::env::temp_dir().join(format!("nm-proxy-test-{}-capture", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = ManifestSettings {
+        capture_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let settings = DaemonSettings {
+        manifests: HashMap::from([("a.json".into(), manifest)]),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("capture", settings);
+    let (mut stream, _) = daemon.connect(true).await;
+    for object in [json!({"message": "hello"}), json!("x".repeat(100_000))] {
+        common::send_nm_object(&mut stream, &object).await.unwrap();
+        common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    }
+    drop(stream);
+    daemon.stop().await.unwrap();
+
+    // Captured uncompressed, in the order the messages were exchanged
+    let capture = std::fs::read_dir(&dir)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    assert!(capture.to_str().unwrap().ends_with(".capture"));
+    let mut messages = Vec::new();
+    for _ in 0..50 {
+        // The capture may still be being written
+        messages = capture::read_capture(&capture).await.unwrap_or_default();
+        if messages.len() == 4 {
+            break;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    let directions: Vec<_> = messages.iter().map(|m| m.direction).collect();
+    let (to_host, from_host) = (Direction::ToHost, Direction::FromHost);
+    assert_eq!(directions, [to_host, from_host, to_host, from_host]);
+    assert_eq!(messages[1].message, json!({"message": "hello"}));
+    assert_eq!(messages[3].message, json!("x".repeat(100_000)));
+    assert!(messages.windows(2).all(|w| w[0].elapsed <= w[1].elapsed));
+
+    // Replaying bounded to after the first exchange prints the second one
+    let since = format!("{:.4}", messages[2].elapsed.as_secs_f64() - 0.0001);
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_daemon"))
+        .args([
+            "--replay",
+            capture.to_str().unwrap(),
+            "--since",
+            since.as_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "{stdout}");
+    assert!(lines[0].contains(" to host \"xxx") && lines[1].contains(" from host \"xxx"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn workers_queue_connections() {
+    let settings = DaemonSettings {
+        workers: NonZeroUsize::new(1),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("workers", settings);
+    let (first, _) = daemon.connect(false).await;
+
+    // The only worker is busy, so the second connection waits in the queue
+    let (second, reply) = {
+        let second = daemon.connect(false);
+        tokio::pin!(second);
+        assert!(time::timeout(Duration::from_millis(300), &mut second)
+            .await
+            .is_err());
+
+        drop(first);
+        second.await
+    };
+    assert_eq!(reply.error, None);
+
+    drop(second);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn connection_events_emitted() {
+    let path = std::env::temp_dir().join(format!("nm-proxy-test-{}-events", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let reader = tokio::net::UnixListener::bind(&path).unwrap();
+
+    let settings = DaemonSettings {
+        event_stream: Some(path.clone()),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("events", settings);
+    let (stream, _) = daemon.connect(false).await;
+    drop(stream);
+
+    let (events, _) = reader.accept().await.unwrap();
+    let mut lines = BufReader::new(events).lines();
+    let mut names = Vec::new();
+    while names.last() != Some(&"teardown".to_string()) {
+        let line = time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["browser"], "firefox");
+        names.push(event["event"].as_str().unwrap().to_string());
+    }
+    assert_eq!(
+        names,
+        ["accepted", "handshake", "launched", "exited", "teardown"]
+    );
+
+    daemon.stop().await.unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn idle_daemon_exits() {
+    let settings = DaemonSettings {
+        idle_exit: Some(1),
+        ..Default::default()
+    };
+    let mut daemon = TestDaemon::start("idle", settings);
+
+    // Sessions keep the daemon running past the idle period
+    let (stream, _) = daemon.connect(false).await;
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(!daemon.handle.is_finished());
+
+    drop(stream);
+    time::timeout(Duration::from_secs(5), &mut daemon.handle)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    std::fs::remove_file(&daemon.path).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_message_sent() {
+    let message = json!({"type": "shutdown"});
+    let settings = DaemonSettings {
+        shutdown_message: Some(message.clone()),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("shutdown", settings);
+    let (mut stream, _) = daemon.connect(false).await;
+
+    let (stopped, received) =
+        tokio::join!(daemon.stop(), common::recv_nm_object::<Value>(&mut stream));
+    stopped.unwrap();
+    assert_eq!(received.unwrap(), message);
+}
+
+#[tokio::test]
+async fn input_discarded_after_host_exit() {
+    let daemon = TestDaemon::start_with("exited", "/bin/true", Default::default());
+    let (stream, _) = daemon.connect(false).await;
+    let (mut reader, mut writer) = stream.into_split();
+
+    // More than fits into the pipe, so the native binary has exited before it all is written
+    let writing = tokio::spawn(async move {
+        let message = "x".repeat(1024 * 1024);
+        common::send_nm_object(&mut writer, message).await
+    });
+
+    // Unread input would reset the connection instead of it closing cleanly
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    writing.await.unwrap().unwrap();
+
+    drop(reader);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn message_size_capped_with_control_frames() {
+    let settings = DaemonSettings {
+        keepalive_interval: Some(60),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start("capped", settings);
+    let (_, reply) = daemon.connect(false).await;
+    assert_eq!(reply.max_message_size, MAX_MESSAGE_SIZE);
+
+    // Length prefixes of data frames must stay clear of the control flag
+    let (_, reply) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: true,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await;
+    assert_eq!(reply.keepalive_interval, Some(60));
+    assert_eq!(reply.max_message_size, MAX_LINK_FRAME_SIZE);
+
+    for (reconnect, terminate) in [(false, true), (true, false)] {
+        let (mut stream, reply) = daemon
+            .connect_with(HandshakeMessage {
+                manifest_name: "a.json".into(),
+                args: vec![],
+                protocol_version: PROTOCOL_VERSION,
+                max_message_size: MAX_MESSAGE_SIZE,
+                compression: false,
+                keepalive: false,
+                client_version: None,
+                reconnect,
+                terminate,
+                profile: None,
+            })
+            .await;
+        assert_eq!(reply.max_message_size, MAX_LINK_FRAME_SIZE);
+
+        // Let the session end with the end marked, rather than breaking it
+        stream.shutdown().await.unwrap();
+        stream.read_to_end(&mut Vec::new()).await.unwrap();
+    }
+
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn session_end_marked() {
+    let daemon = TestDaemon::start_with("ended", "/bin/true", Default::default());
+    let (mut stream, reply) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: true,
+            terminate: true,
+            profile: None,
+        })
+        .await;
+    assert!(reply.reconnect);
+
+    // The native binary exits right away, which reconnecting clients must not retry
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert_eq!(rest, SESSION_END.to_ne_bytes());
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn terminated_client_stops_host() {
+    let daemon = TestDaemon::start_with("terminate", "/bin/sh", Default::default());
+    let (mut stream, reply) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec!["-c".into(), "exec sleep 30".into()],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: true,
+            profile: None,
+        })
+        .await;
+    assert!(reply.terminate);
+
+    // The native binary ignores its input, so only terminating it ends the session
+    stream.write_all(&TERMINATE.to_ne_bytes()).await.unwrap();
+    let mut rest = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn resource_limits_applied() {
+    let manifest = ManifestSettings {
+        open_files_limit: Some(42),
+        ..Default::default()
+    };
+    let settings = DaemonSettings {
+        manifests: HashMap::from([("a.json".into(), manifest)]),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start_with("limits", "/bin/sh", settings);
+    let (mut stream, _) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![
+                "-c".into(),
+                r"printf '\002\000\000\000%s' $(ulimit -n)".into(),
+            ],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await;
+
+    // The limit is output as a JSON number in a two byte native message
+    let limit = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    assert_eq!(limit, json!(42));
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn output_forwarded_after_stdin_closed() {
+    let daemon = TestDaemon::start_with("stdin", "/bin/sh", Default::default());
+    let (mut stream, _) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![
+                "-c".into(),
+                r"exec 0<&-; sleep 0.2; printf '\001\000\000\0001'; sleep 0.2; printf '\001\000\000\0002'"
+                    .into(),
+            ],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await;
+
+    // Writing to the closed stdin of the native binary fails, but its output keeps coming
+    time::sleep(Duration::from_millis(100)).await;
+    common::send_nm_object(&mut stream, &json!("ignored"))
+        .await
+        .unwrap();
+    for expected in [json!(1), json!(2)] {
+        let message = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+        assert_eq!(message, expected);
+    }
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn extension_id_passed_in_env() {
+    let settings = DaemonSettings {
+        extension_id_env: true,
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start_with("extension", "/bin/sh", settings);
+    let origin = "chrome-extension://abc/";
+    let (mut stream, _) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![
+                "-c".into(),
+                format!(
+                    r#"printf '\{:o}\000\000\000"%s"' "$NM_PROXY_EXTENSION_ID""#,
+                    origin.len() + 2
+                ),
+                origin.into(), // Chromium-style origin, set as $0 of the shell
+            ],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await;
+
+    let id = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    assert_eq!(id, json!(origin));
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn profile_passed_in_env() {
+    let daemon = TestDaemon::start_with("profile", "/bin/sh", Default::default());
+    let (mut stream, _) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec![
+                "-c".into(),
+                r#"printf '\006\000\000\000"%s"' "$NM_PROXY_PROFILE""#.into(),
+            ],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: Some("work".into()),
+        })
+        .await;
+
+    let profile = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    assert_eq!(profile, json!("work"));
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn oneshot_session_ended() {
+    let manifest = ManifestSettings {
+        mode: ManifestMode::Oneshot,
+        ..Default::default()
+    };
+    let settings = DaemonSettings {
+        manifests: HashMap::from([("a.json".into(), manifest)]),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start_with("oneshot", "/bin/sh", settings);
+    let (mut stream, _) = daemon
+        .connect_with(HandshakeMessage {
+            manifest_name: "a.json".into(),
+            args: vec!["-c".into(), r"printf '\002\000\000\00042'; sleep 30".into()],
+            protocol_version: PROTOCOL_VERSION,
+            max_message_size: MAX_MESSAGE_SIZE,
+            compression: false,
+            keepalive: false,
+            client_version: None,
+            reconnect: false,
+            terminate: false,
+            profile: None,
+        })
+        .await;
+
+    // The session ends after the response instead of waiting for the native binary to exit
+    let response = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    assert_eq!(response, json!(42));
+    let mut rest = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(rest.is_empty());
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn persistent_host_reused() {
+    let manifest = ManifestSettings {
+        persistent_ttl: Some(1),
+        ..Default::default()
+    };
+    let settings = DaemonSettings {
+        manifests: HashMap::from([("a.json".into(), manifest)]),
+        ..Default::default()
+    };
+    let daemon = TestDaemon::start_with("persistent", "/bin/sh", settings);
+    let launches = daemon.path.with_extension("launches");
+    let handshake = || HandshakeMessage {
+        manifest_name: "a.json".into(),
+        args: vec![
+            "-c".into(),
+            format!("echo $$ >> {}; exec cat", launches.display()),
+        ],
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: MAX_MESSAGE_SIZE,
+        compression: false,
+        keepalive: false,
+        client_version: None,
+        reconnect: false,
+        terminate: false,
+        profile: None,
+    };
+
+    // Both sessions are served by the same native binary
+    for message in ["first", "second"] {
+        let (mut stream, _) = daemon.connect_with(handshake()).await;
+        common::send_nm_object(&mut stream, &json!(message))
+            .await
+            .unwrap();
+        let echoed = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+        assert_eq!(echoed, json!(message));
+        drop(stream);
+        time::sleep(Duration::from_millis(100)).await; // Let the daemon park it
+    }
+
+    let pids = std::fs::read_to_string(&launches).unwrap();
+    let _ = std::fs::remove_file(&launches);
+    let pid: i32 = match pids.lines().collect::<Vec<_>>()[..] {
+        [pid] => pid.parse().unwrap(),
+        _ => panic!("native binary launched more than once: {pids:?}"),
+    };
+
+    // The idle native binary is stopped once the TTL has passed
+    let alive = || kill(Pid::from_raw(pid), None).is_ok();
+    assert!(alive());
+    time::sleep(Duration::from_millis(1500)).await;
+    assert!(!alive(), "native binary still running after its TTL");
+
+    daemon.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn failed_listener_restarted() {
+    // Accepting fails on a socket that isn't listening yet
+    let path = std::env::temp_dir().join(format!(
+        "nm-proxy-test-{}-restart.socket",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let fd = socket::socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::empty(),
+        None,
+    )
+    .unwrap();
+    socket::bind(fd.as_raw_fd(), &UnixAddr::new(&path).unwrap()).unwrap();
+
+    let settings = DaemonSettings {
+        listener_restarts: 3,
+        ..Default::default()
+    };
+    let daemon = TestDaemon::serve(path, fd.try_clone().unwrap(), "/bin/cat", settings);
+    time::sleep(Duration::from_millis(50)).await;
+    socket::listen(&fd, Backlog::MAXCONN).unwrap();
+
+    // The restarted listener serves connections
+    let (mut stream, reply) = daemon.connect(false).await;
+    assert_eq!(reply.error, None);
+    common::send_nm_object(&mut stream, &json!("restarted"))
+        .await
+        .unwrap();
+    let echoed = common::recv_nm_object::<Value>(&mut stream).await.unwrap();
+    assert_eq!(echoed, json!("restarted"));
+
+    drop(stream);
+    daemon.stop().await.unwrap();
+}
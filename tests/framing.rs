@@ -0,0 +1,106 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::ErrorKind;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NativeEndian};
+use nm_proxy::common;
+use nm_proxy::common::constants::MAX_MESSAGE_SIZE;
+use nm_proxy::common::FrameCodec;
+use serde_json::{json, Value};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+async fn round_trip<B: ByteOrder>(object: Value) -> Value {
+    let (mut a, mut b) = duplex(1024);
+    common::send_nm_object_with::<B>(&mut a, &object)
+        .await
+        .unwrap();
+    common::recv_nm_object_with::<B, _>(&mut b).await.unwrap()
+}
+
+#[tokio::test]
+async fn round_trip_little_endian() {
+    let object = json!({"message": "hello", "n": 42});
+    assert_eq!(round_trip::<LittleEndian>(object.clone()).await, object);
+}
+
+#[tokio::test]
+async fn round_trip_native_endian() {
+    let object = json!(["hello", 42]);
+    assert_eq!(round_trip::<NativeEndian>(object.clone()).await, object);
+}
+
+#[tokio::test]
+async fn default_framing_is_native_endian() {
+    let (mut a, mut b) = duplex(1024);
+    common::send_nm_object(&mut a, "hi").await.unwrap();
+
+    let mut len_buf = [0; 4];
+    b.read_exact(&mut len_buf).await.unwrap();
+    assert_eq!(NativeEndian::read_u32(&len_buf), 4); // "hi" with quotes
+}
+
+#[tokio::test]
+async fn byte_swapped_length_detected() {
+    let (mut a, mut b) = duplex(1024);
+    let object = "x".repeat(200); // Swapped length is way past the maximum
+    common::send_nm_object_with::<BigEndian>(&mut a, object)
+        .await
+        .unwrap();
+
+    let result = common::recv_nm_object_with::<LittleEndian, Value>(&mut b).await;
+    assert!(format!("{:#}", result.unwrap_err()).contains("byte-swapped"));
+}
+
+#[tokio::test]
+async fn compressed_frames_round_trip() {
+    let object = json!({"message": "hello ".repeat(100)});
+    let (mut browser, mut client_in) = duplex(4096);
+    let (mut client_out, mut daemon_in) = duplex(4096);
+    let (mut daemon_out, mut host) = duplex(4096);
+
+    common::send_nm_object(&mut browser, &object).await.unwrap();
+    drop(browser);
+
+    let compressed = common::forward_nm_frames_with(
+        &mut client_in,
+        &mut client_out,
+        MAX_MESSAGE_SIZE,
+        FrameCodec::Compress,
+    )
+    .await
+    .unwrap();
+    drop(client_out);
+
+    let decompressed = common::forward_nm_frames_with(
+        &mut daemon_in,
+        &mut daemon_out,
+        MAX_MESSAGE_SIZE,
+        FrameCodec::Decompress,
+    )
+    .await
+    .unwrap();
+
+    // Both ends count the uncompressed bytes
+    assert_eq!(compressed, decompressed);
+    assert_eq!(
+        common::recv_nm_object::<Value>(&mut host).await.unwrap(),
+        object
+    );
+}
+
+#[tokio::test]
+async fn decompressed_size_limited() {
+    let (mut a, mut b) = duplex(4096);
+    let (mut c, _d) = duplex(4096);
+
+    let body = lz4_flex::compress_prepend_size(&[b' '; 1000]);
+    a.write_all(&(body.len() as u32).to_ne_bytes())
+        .await
+        .unwrap();
+    a.write_all(&body).await.unwrap();
+    drop(a);
+
+    let result = common::forward_nm_frames_with(&mut b, &mut c, 100, FrameCodec::Decompress).await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,212 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use nm_proxy::common;
+use nm_proxy::common::constants::*;
+use nm_proxy::common::{HandshakeMessage, HandshakeReply};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+
+fn handshake(args: Vec<String>) -> HandshakeMessage {
+    HandshakeMessage {
+        manifest_name: "a.json".into(),
+        args,
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: MAX_MESSAGE_SIZE,
+        compression: true,
+        keepalive: true,
+        client_version: Some("0.1.0".into()),
+        reconnect: true,
+        terminate: true,
+        profile: Some("work".into()),
+    }
+}
+
+/// Sends `message` from the client end of a socket pair and receives it on the daemon end
+async fn round_trip(message: HandshakeMessage) -> HandshakeMessage {
+    let (mut client, mut daemon) = UnixStream::pair().unwrap();
+    let (sent, received) = tokio::join!(
+        common::send_nm_object(&mut client, &message),
+        common::recv_nm_object(&mut daemon)
+    );
+
+    sent.unwrap();
+    received.unwrap()
+}
+
+#[tokio::test]
+async fn empty_args() {
+    let message = handshake(vec![]);
+    assert_eq!(round_trip(message.clone()).await, message);
+}
+
+#[tokio::test]
+async fn unicode_args() {
+    let message = handshake(vec![
+        "/home/käyttäjä/.mozilla/native-messaging-hosts/a.json".into(),
+        "拡張機能@example.org 🦀".into(),
+    ]);
+    assert_eq!(round_trip(message.clone()).await, message);
+}
+
+#[tokio::test]
+async fn large_args() {
+    let message = handshake(vec!["x".repeat(1024 * 1024), "y".into()]);
+    assert_eq!(round_trip(message.clone()).await, message);
+}
+
+#[tokio::test]
+async fn legacy_handshake() {
+    let (mut client, mut daemon) = UnixStream::pair().unwrap();
+    let body = br#"{"manifest_name":"a.json","args":["/a.json","ext@id"]}"#;
+    client
+        .write_all(&(body.len() as u32).to_ne_bytes())
+        .await
+        .unwrap();
+    client.write_all(body).await.unwrap();
+
+    let message: HandshakeMessage = common::recv_nm_object(&mut daemon).await.unwrap();
+    assert_eq!(message.protocol_version, 0);
+    assert_eq!(message.max_message_size, MAX_MESSAGE_SIZE);
+    assert!(!message.compression);
+    assert!(!message.keepalive);
+    assert_eq!(message.client_version, None);
+    assert!(!message.reconnect);
+    assert!(!message.terminate);
+    assert_eq!(message.profile, None);
+}
+
+#[tokio::test]
+async fn reply_round_trip() {
+    let (mut daemon, mut client) = UnixStream::pair().unwrap();
+    let reply = HandshakeReply {
+        protocol_version: PROTOCOL_VERSION,
+        max_message_size: 1024,
+        error: Some("rejected".into()),
+        compression: false,
+        keepalive_interval: Some(30),
+        reconnect: true,
+        terminate: true,
+    };
+
+    common::send_nm_object(&mut daemon, &reply).await.unwrap();
+    let received: HandshakeReply = common::recv_nm_object(&mut client).await.unwrap();
+    assert_eq!(received, reply);
+}
+
+#[test]
+fn legacy_handshake_serialized() {
+    let message = handshake(vec!["/a.json".into(), "ext@id".into()]).legacy();
+    assert_eq!(
+        serde_json::to_value(message).unwrap(),
+        json!({"manifest_name": "a.json", "args": ["/a.json", "ext@id"]})
+    );
+}
+
+/// Handshake as accepted by daemons predating negotiation
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BaselineHandshake {
+    manifest_name: String,
+    args: Vec<String>,
+}
+
+/// Proxy client of a session of `a.json`, connecting to a socket in `runtime_dir`
+fn spawn_client(runtime_dir: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["/a.json", "ext@id"])
+        .env("XDG_RUNTIME_DIR", runtime_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn legacy_daemon_fallback() {
+    let dir = std::env::temp_dir().join(format!("nm-proxy-test-{}-legacy", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let listener = UnixListener::bind(dir.join(common::socket_file_name("firefox"))).unwrap();
+
+    let mut client = spawn_client(&dir);
+
+    // The negotiating handshake is rejected by closing the connection
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert!(common::recv_nm_object::<BaselineHandshake>(&mut stream)
+        .await
+        .is_err());
+    drop(stream);
+
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let handshake: BaselineHandshake = common::recv_nm_object(&mut stream).await.unwrap();
+    assert_eq!(handshake.manifest_name, "a.json");
+    assert_eq!(handshake.args[1], "ext@id");
+
+    // Messages are forwarded as is in both directions
+    let mut stdin = client.stdin.take().unwrap();
+    let mut stdout = client.stdout.take().unwrap();
+    common::send_nm_object(&mut stdin, &json!("ping"))
+        .await
+        .unwrap();
+    let message: Value = common::recv_nm_object(&mut stream).await.unwrap();
+    assert_eq!(message, json!("ping"));
+    common::send_nm_object(&mut stream, &json!("pong"))
+        .await
+        .unwrap();
+    let message: Value = common::recv_nm_object(&mut stdout).await.unwrap();
+    assert_eq!(message, json!("pong"));
+
+    drop(stdin);
+    let output = client.wait_with_output().await.unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("legacy handshake"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Runtime directory with a stale socket of a daemon that is no longer running
+fn stale_runtime_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nm-proxy-test-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    drop(
+        std::os::unix::net::UnixListener::bind(dir.join(common::socket_file_name("firefox")))
+            .unwrap(),
+    );
+    dir
+}
+
+#[tokio::test]
+async fn restarted_daemon_reached() {
+    let dir = stale_runtime_dir("restarted");
+    let mut client = spawn_client(&dir);
+
+    // The daemon comes back while the client is rescanning
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let path = dir.join(common::socket_file_name("firefox"));
+    std::fs::remove_file(&path).unwrap();
+    let listener = UnixListener::bind(&path).unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let handshake: HandshakeMessage = common::recv_nm_object(&mut stream).await.unwrap();
+    assert_eq!(handshake.manifest_name, "a.json");
+
+    client.kill().await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn stale_sockets_given_up() {
+    let dir = stale_runtime_dir("stale");
+    let output = spawn_client(&dir).wait_with_output().await.unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Connection refused by"), "{stderr}");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,127 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use nm_proxy::common;
+use nm_proxy::common::constants::MAX_MESSAGE_SIZE;
+use nm_proxy::common::keepalive::{Link, CONTROL_FLAG, MAX_LINK_FRAME_SIZE};
+use nm_proxy::common::FrameCodec;
+use serde_json::{json, Value};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::{select, time};
+
+const FRAMING: (u32, FrameCodec) = (MAX_MESSAGE_SIZE, FrameCodec::Plain);
+const INTERVAL: Duration = Duration::from_millis(20);
+
+#[tokio::test]
+async fn control_frames_not_forwarded() {
+    let (mut peer, link_end) = duplex(1024);
+    let (mut link_rx, link_tx) = tokio::io::split(link_end);
+    let (mut local_out, mut local_in) = duplex(1024);
+    let link = Link::new(link_tx);
+
+    // Ping followed by a data frame
+    peer.write_all(&(1u32 << 31 | 1).to_ne_bytes())
+        .await
+        .unwrap();
+    common::send_nm_object(&mut peer, json!({"hello": "world"}))
+        .await
+        .unwrap();
+
+    select! {
+        res = link.forward_to(&mut link_rx, &mut local_out, FRAMING) => panic!("{res:?}"),
+        _ = time::sleep(INTERVAL) => (),
+    }
+
+    // The ping is answered, and only the data frame is forwarded
+    let mut pong = [0; 4];
+    peer.read_exact(&mut pong).await.unwrap();
+    assert_eq!(u32::from_ne_bytes(pong), 1 << 31 | 2);
+
+    let received: Value = common::recv_nm_object(&mut local_in).await.unwrap();
+    assert_eq!(received, json!({"hello": "world"}));
+}
+
+#[tokio::test]
+async fn data_frames_capped_below_control_flag() {
+    let framing = (MAX_LINK_FRAME_SIZE, FrameCodec::Plain);
+    let (link_end, mut peer) = duplex(1024);
+    let (mut link_rx, link_tx) = tokio::io::split(link_end);
+    let link = Link::new(link_tx);
+
+    // A data frame this large would pass as a control frame on the link
+    let mut input = &CONTROL_FLAG.to_ne_bytes()[..];
+    let err = link.forward_from(&mut input, framing).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    // The largest data frame is forwarded as such, both frames here end after their length
+    let mut input = &MAX_LINK_FRAME_SIZE.to_ne_bytes()[..];
+    let err = link.forward_from(&mut input, framing).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    let mut prefix = [0; 4];
+    peer.read_exact(&mut prefix).await.unwrap();
+    assert_eq!(u32::from_ne_bytes(prefix), MAX_LINK_FRAME_SIZE);
+    peer.write_all(&prefix).await.unwrap();
+    drop(peer);
+    let mut out = tokio::io::sink();
+    let err = link
+        .forward_to(&mut link_rx, &mut out, framing)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+}
+
+#[tokio::test]
+async fn responsive_peer_kept_alive() {
+    let (a, b) = duplex(1024);
+    let (mut a_rx, a_tx) = tokio::io::split(a);
+    let (mut b_rx, b_tx) = tokio::io::split(b);
+    let (a_link, b_link) = (Link::new(a_tx), Link::new(b_tx));
+    let (mut a_out, mut b_out) = (tokio::io::sink(), tokio::io::sink());
+
+    select! {
+        res = a_link.keepalive(INTERVAL) => panic!("{res:?}"),
+        res = a_link.forward_to(&mut a_rx, &mut a_out, FRAMING) => panic!("{res:?}"),
+        res = b_link.forward_to(&mut b_rx, &mut b_out, FRAMING) => panic!("{res:?}"),
+        _ = time::sleep(INTERVAL * 10) => (),
+    }
+}
+
+#[tokio::test]
+async fn dead_peer_times_out() {
+    let (_peer, link_end) = duplex(1024); // Never answers
+    let (mut link_rx, link_tx) = tokio::io::split(link_end);
+    let link = Link::new(link_tx);
+    let mut local_out = tokio::io::sink();
+
+    let err = select! {
+        res = link.keepalive(INTERVAL) => res.unwrap_err(),
+        res = link.forward_to(&mut link_rx, &mut local_out, FRAMING) => panic!("{res:?}"),
+    };
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn latency_measured() {
+    let (local, link_end) = duplex(1024);
+    let (_link_rx, link_tx) = tokio::io::split(link_end);
+    let link = Link::new(link_tx).with_latency(["out", "in"], Duration::MAX);
+
+    let (mut source, mut sink) = duplex(1024);
+    for i in 0..3 {
+        common::send_nm_object(&mut source, json!({"message": i}))
+            .await
+            .unwrap();
+    }
+    drop(source);
+    link.forward_from(&mut sink, FRAMING).await.unwrap();
+    drop(local);
+
+    // Only the direction that messages were forwarded in has a summary
+    let (out, into) = link.latency().unwrap();
+    assert!(out.summary().unwrap().starts_with("3 messages out: mean"));
+    assert_eq!(into.summary(), None);
+}
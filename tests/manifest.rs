@@ -0,0 +1,173 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use nm_proxy::common::manifest::*;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = r#"{
+  "name": "org.example.host",
+  "path": "/usr/bin/host",
+  "type": "stdio"
+}"#;
+
+#[test]
+fn file_name_from_name() {
+    // Browsers look for "<name>.json", regardless of the source file name
+    let manifest = parse_manifest(MANIFEST, false).unwrap();
+    assert_eq!(
+        manifest_file_name(&manifest).as_deref(),
+        Some("org.example.host.json")
+    );
+}
+
+#[test]
+fn file_name_missing() {
+    let manifest = parse_manifest(r#"{"path": "/usr/bin/host"}"#, false).unwrap();
+    assert_eq!(manifest_file_name(&manifest), None);
+}
+
+#[test]
+fn key_order_preserved() {
+    // Deployed manifests should only differ from their source where they are modified
+    let manifest =
+        parse_manifest(r#"{"type": "stdio", "name": "b", "path": "/a"}"#, false).unwrap();
+    assert_eq!(
+        serde_json::to_string(&manifest).unwrap(),
+        r#"{"type":"stdio","name":"b","path":"/a"}"#
+    );
+}
+
+#[test]
+fn byte_order_mark_and_crlf() {
+    let contents = format!("\u{feff}{}", MANIFEST.replace('\n', "\r\n"));
+    let manifest = parse_manifest(&contents, false).unwrap();
+    assert_eq!(manifest["path"], "/usr/bin/host");
+
+    // Line comments may end with CRLF too
+    let contents = "\u{feff}{\r\n  // Comment\r\n  \"type\": \"stdio\"\r\n}\r\n";
+    let manifest = parse_manifest(contents, true).unwrap();
+    assert_eq!(manifest["type"], "stdio");
+}
+
+#[test]
+fn comments_stripped() {
+    let contents =
+        "{\n  /* Block\n  comment */ \"name\": \"a\", // Line comment\n  \"type\": \"stdio\"\n}";
+    let manifest = parse_manifest(contents, true).unwrap();
+    assert_eq!(manifest, json!({"name": "a", "type": "stdio"}));
+    assert!(parse_manifest(contents, false).is_err());
+}
+
+#[test]
+fn comment_delimiters_in_strings_kept() {
+    let contents = r#"{"path": "/a//b/*c*/", "description": "\"// d"} // e"#;
+    let manifest = parse_manifest(contents, true).unwrap();
+    assert_eq!(manifest["path"], "/a//b/*c*/");
+    assert_eq!(manifest["description"], "\"// d");
+}
+
+#[test]
+fn error_position_after_comments() {
+    // Columns count the blanked out comments, multi-byte characters included
+    let contents = "{\n  /* ä */ \"a\": 1 x\n}";
+    let error = parse_manifest(contents, true).unwrap_err();
+    assert_eq!(error.to_string(), "line 2, column 19");
+}
+
+#[test]
+fn proxied_manifest_rewritten() {
+    let manifest = parse_manifest(MANIFEST, false).unwrap();
+    let client = Path::new("/nmh/nm-proxy-client");
+    let proxied = proxy_manifest(manifest, "host.json", |_| None, client).unwrap();
+    assert_eq!(proxied.file_name, "org.example.host.json");
+    assert_eq!(proxied.binary, "/usr/bin/host");
+    assert_eq!(proxied.manifest["path"], "/nmh/nm-proxy-client");
+    assert_eq!(proxied.warnings.len(), 1); // Deployed under a different name
+
+    let manifest = parse_manifest(MANIFEST, false).unwrap();
+    let binary = |n: &str| (n == "org.example.host.json").then(|| PathBuf::from("/opt/host"));
+    let proxied = proxy_manifest(manifest, "org.example.host.json", binary, client).unwrap();
+    assert_eq!(proxied.binary, "/opt/host");
+    assert!(proxied.warnings.is_empty());
+}
+
+#[test]
+fn manifest_names_validated() {
+    for name in ["a", "org.example.host_2", "a.b.c"] {
+        assert!(valid_manifest_name(name), "{name}");
+    }
+    for name in ["", ".a", "a.", "a..b", "a/b", "..", "/a", "A", "a-b", "a b"] {
+        assert!(!valid_manifest_name(name), "{name}");
+    }
+}
+
+#[test]
+fn proxied_manifest_rejected() {
+    let client = Path::new("/nmh/nm-proxy-client");
+    for (contents, error) in [
+        (r#"{"name": "a", "type": "stdio"}"#, "\"path\" key missing"),
+        (
+            r#"{"name": "a", "path": "/a", "type": "x"}"#,
+            "only type \"stdio\"",
+        ),
+        (
+            r#"{"name": "a", "path": ["/a"], "type": "stdio"}"#,
+            "found an array",
+        ),
+        (r#"{"name": "a", "path": "/a"}"#, "\"type\" key missing"),
+        (
+            r#"{"name": "a", "path": "/a", "type": {}}"#,
+            "found an object",
+        ),
+        (
+            r#"{"name": "../../.bashrc", "path": "/a", "type": "stdio"}"#,
+            "Invalid app manifest name",
+        ),
+        (
+            r#"{"name": "/etc/a", "path": "/a", "type": "stdio"}"#,
+            "Invalid app manifest name",
+        ),
+    ] {
+        let manifest = parse_manifest(contents, false).unwrap();
+        let result = proxy_manifest(manifest, "a.json", |_| None, client);
+        assert!(result.err().unwrap().to_string().contains(error));
+    }
+}
+
+#[test]
+fn manifest_values_overridden() {
+    let mut manifest = parse_manifest(
+        r#"{"name": "a", "description": "A", "allowed_origins": ["chrome-extension://a/"]}"#,
+        false,
+    )
+    .unwrap();
+    let overrides = BTreeMap::from([
+        ("/description".into(), json!("A for Chromium")),
+        ("/allowed_origins/-".into(), json!("chrome-extension://b/")),
+        ("/x~1y".into(), json!({"z": 1})),
+    ]);
+    override_values(&mut manifest, &overrides).unwrap();
+    assert_eq!(
+        manifest,
+        json!({
+            "name": "a",
+            "description": "A for Chromium",
+            "allowed_origins": ["chrome-extension://a/", "chrome-extension://b/"],
+            "x/y": {"z": 1},
+        })
+    );
+
+    for (pointer, error) in [
+        ("description", "must start with /"),
+        ("/path", "managed by nm-proxy"),
+        ("/type/x", "managed by nm-proxy"),
+        ("/allowed_origins/5", "out of bounds"),
+        ("/missing/x", "not found"),
+    ] {
+        let overrides = BTreeMap::from([(pointer.into(), json!(1))]);
+        let result = override_values(&mut manifest, &overrides);
+        assert!(result.unwrap_err().to_string().contains(error), "{pointer}");
+    }
+}
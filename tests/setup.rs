@@ -0,0 +1,287 @@
+// (c) Dennis Marttinen 2023
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+/// Home directory for setup runs, removed when dropped
+struct TestHome {
+    path: PathBuf,
+}
+
+impl TestHome {
+    /// Creates the home with a single source app manifest, `config` follows `[daemon]`
+    fn new(name: &str, config: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("nm-proxy-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        for dir in [
+            ".config/nm-proxy/manifest",
+            ".local/share/flatpak/overrides",
+            "run",
+        ] {
+            fs::create_dir_all(path.join(dir)).unwrap();
+        }
+
+        fs::write(
+            path.join(".config/nm-proxy/config.toml"),
+            format!(
+                "[daemon]\nproxy_client = {:?}\n{config}",
+                env!("CARGO_BIN_EXE_client")
+            ),
+        )
+        .unwrap();
+        fs::write(
+            path.join(".config/nm-proxy/manifest/a.json"),
+            r#"{"name": "a", "description": "A", "path": "/bin/cat", "type": "stdio"}"#,
+        )
+        .unwrap();
+        Self { path }
+    }
+
+    fn setup(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_setup"));
+        command
+            .args(args)
+            .env("HOME", &self.path)
+            .env("XDG_CONFIG_HOME", self.path.join(".config"))
+            .env("XDG_DATA_HOME", self.path.join(".local/share"))
+            .env("XDG_STATE_HOME", self.path.join(".local/state"))
+            .env("XDG_RUNTIME_DIR", self.path.join("run"))
+            .env("NM_PROXY_FLATPAK_BASE", self.path.join(".var/app"))
+            .env("NO_COLOR", "1");
+        command
+    }
+}
+
+impl Drop for TestHome {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+const NESTED_BROWSER: &str = r#"
+[browsers.firefox]
+app_id = "org.mozilla.firefox"
+nmh_dir = ".mozilla/nested/native-messaging-hosts"
+"#;
+
+#[test]
+fn nested_nmh_dir_created() {
+    let home = TestHome::new("nested", NESTED_BROWSER);
+    let output = home.setup(&[]);
+    assert!(output.status.success(), "{output:?}");
+
+    let nmh_dir = home
+        .path
+        .join(".var/app/org.mozilla.firefox/.mozilla/nested/native-messaging-hosts");
+    assert!(nmh_dir.join("a.json").is_file());
+}
+
+#[test]
+fn missing_parent_dirs_required() {
+    let config = format!("[setup]\nrequire_parent_dirs = true\n{NESTED_BROWSER}");
+    let home = TestHome::new("parents", &config);
+    let output = home.setup(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to create NMH directory"));
+}
+
+#[test]
+fn identical_proxy_client_kept() {
+    let home = TestHome::new("client", NESTED_BROWSER);
+    let up_to_date = |output: &Output| {
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8_lossy(&output.stdout).contains("is up to date")
+    };
+
+    assert!(!up_to_date(&home.setup(&[])));
+    assert!(up_to_date(&home.setup(&[])));
+    assert!(!up_to_date(&home.setup(&["--force"])));
+}
+
+#[test]
+fn busy_writes_retried() {
+    let config =
+        format!("[logging]\nlevel = \"debug\"\n[setup]\nwrite_retries = 2\n{NESTED_BROWSER}");
+    let home = TestHome::new("busy", &config);
+    let nmh_dir = home
+        .path
+        .join(".var/app/org.mozilla.firefox/.mozilla/nested/native-messaging-hosts");
+    fs::create_dir_all(&nmh_dir).unwrap();
+    let client = nmh_dir.join("nm-proxy-client");
+    fs::copy("/bin/sleep", &client).unwrap();
+
+    // An executable can't be written while it runs, which outlasts the retries here
+    let mut busy = Command::new(&client).arg("10").spawn().unwrap();
+    let output = home.setup(&[]);
+    busy.kill().unwrap();
+    busy.wait().unwrap();
+    let logs = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(logs.contains("(2/2)") && !logs.contains("(3/2)"), "{logs}");
+    assert!(logs.contains("Text file busy"), "{logs}");
+
+    // Here it exits during the retries
+    let mut busy = Command::new(&client).arg("10").spawn().unwrap();
+    let mut setup = home.command(&[]).stdout(Stdio::piped()).spawn().unwrap();
+    let stdout = BufReader::new(setup.stdout.take().unwrap());
+    let mut lines = stdout.lines().map(Result::unwrap);
+    assert!(lines.any(|l| l.contains("retrying write")));
+    busy.kill().unwrap();
+    busy.wait().unwrap();
+    lines.for_each(drop);
+    assert!(setup.wait().unwrap().success());
+}
+
+#[test]
+fn writable_manifest_refused() {
+    let config = format!("[setup]\nstrict_permissions = true\n{NESTED_BROWSER}");
+    for (name, config) in [("writable", NESTED_BROWSER), ("strict", config.as_str())] {
+        let home = TestHome::new(name, config);
+        let manifest = home.path.join(".config/nm-proxy/manifest/a.json");
+        fs::set_permissions(manifest, fs::Permissions::from_mode(0o666)).unwrap();
+
+        let output = home.setup(&[]);
+        let logs =
+            String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+        assert!(logs.contains("is writable by all users"), "{logs}");
+        assert_eq!(output.status.success(), name == "writable");
+    }
+}
+
+#[test]
+fn incremental_redeploys_changed() {
+    let config = format!("[logging]\nlevel = \"debug\"\n{NESTED_BROWSER}");
+    let home = TestHome::new("incremental", &config);
+    let source = home.path.join(".config/nm-proxy/manifest/a.json");
+    let deployed = home
+        .path
+        .join(".var/app/org.mozilla.firefox/.mozilla/nested/native-messaging-hosts/a.json");
+    let skipped = |args: &[&str]| {
+        let output = home.setup(args);
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8_lossy(&output.stdout).contains("unchanged, not redeploying")
+    };
+
+    assert!(!skipped(&["--incremental"]));
+    assert!(skipped(&["--incremental"]));
+    let index = home
+        .path
+        .join(".local/state/nm-proxy/nm-proxy-deployment.json");
+    assert!(index.is_file());
+
+    // Changed sources and deployed manifests removed in the meantime are redeployed
+    let manifest = r#"{"name": "a", "description": "B", "path": "/bin/cat", "type": "stdio"}"#;
+    fs::write(&source, manifest).unwrap();
+    assert!(!skipped(&["--incremental"]));
+    assert!(fs::read_to_string(&deployed).unwrap().contains(r#""B""#));
+    fs::remove_file(&deployed).unwrap();
+    assert!(!skipped(&["--incremental"]));
+    assert!(deployed.is_file());
+
+    // Forcing ignores the index
+    assert!(skipped(&["--incremental"]));
+    assert!(!skipped(&["--incremental", "--force"]));
+}
+
+#[test]
+fn unchanged_manifest_checked() {
+    let config = format!("[setup]\nstrict_permissions = true\n{NESTED_BROWSER}");
+    let home = TestHome::new("unchanged", &config);
+    for _ in 0..2 {
+        let output = home.setup(&["--incremental"]);
+        assert!(output.status.success(), "{output:?}");
+    }
+
+    // The source is unchanged, but became writable by all users
+    let source = home.path.join(".config/nm-proxy/manifest/a.json");
+    fs::set_permissions(source, fs::Permissions::from_mode(0o666)).unwrap();
+    let output = home.setup(&["--incremental"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is writable by all users"));
+}
+
+#[test]
+fn failed_browser_not_overridden() {
+    let config = format!(
+        "{NESTED_BROWSER}
+[browsers.chromium]
+app_id = \"org.chromium.Chromium\"
+nmh_dir = \"config/chromium/NativeMessagingHosts\"
+"
+    );
+    let home = TestHome::new("overrides", &config);
+    let broken = home.path.join(".config/nm-proxy/manifest/chromium");
+    fs::create_dir(&broken).unwrap();
+    fs::write(broken.join("b.json"), "{").unwrap();
+
+    // Chromium fails on its manifest, Firefox is still set up
+    let output = home.setup(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Setup failed for browser chromium"));
+    let overrides = home.path.join(".local/share/flatpak/overrides");
+    assert!(overrides.join("org.mozilla.firefox").is_file());
+    assert!(!overrides.join("org.chromium.Chromium").exists());
+}
+
+#[test]
+fn json_with_plain_output_rejected() {
+    let home = TestHome::new("json", NESTED_BROWSER);
+    let manifest = home.path.join(".config/nm-proxy/manifest/a.json");
+    let manifest = manifest.to_str().unwrap();
+    for args in [&["--print-config"][..], &["--check-manifest", manifest]] {
+        let output = home.setup(&[args, &["--json"]].concat());
+        assert!(!output.status.success());
+        assert!(output.stdout.is_empty(), "{output:?}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--json can't be combined"));
+    }
+}
+
+#[test]
+fn invalid_env_reported() {
+    let home = TestHome::new("env", NESTED_BROWSER);
+    let mut command = home.command(&[]);
+    command.env("XDG_CONFIG_HOME", OsStr::from_bytes(b"/tmp/\xff"));
+    let output = command.output().unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("XDG_CONFIG_HOME contains invalid UTF-8"),
+        "{stderr}"
+    );
+
+    // Unlike an unset variable, for which exporting it is suggested
+    let output = home
+        .command(&[])
+        .env_remove("XDG_RUNTIME_DIR")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("XDG_RUNTIME_DIR is not set"), "{stderr}");
+}
+
+#[test]
+fn starter_config_written() {
+    let home = TestHome::new("init", "");
+    fs::create_dir_all(home.path.join(".var/app/org.mozilla.firefox")).unwrap();
+    let output = home.setup(&["--init"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+
+    let output = home.setup(&["--init", "--force"]);
+    assert!(output.status.success(), "{output:?}");
+    let config = fs::read_to_string(home.path.join(".config/nm-proxy/config.toml")).unwrap();
+    assert!(config.contains("[browsers.firefox]\napp_id = \"org.mozilla.firefox\""));
+    assert!(!config.contains("chromium"));
+}